@@ -1,6 +1,6 @@
 use alphabeta::{
     alphabeta::steady_state,
-    divergence::{divergence, genmatrix, matrix_power},
+    divergence::{divergence, genmatrix, matrix_power, matrix_powers, time_spans},
     pedigree::Pedigree,
     structs::Model,
 };
@@ -27,17 +27,17 @@ fn criterion_benchmark(c: &mut Criterion) {
         b.iter(|| genmatrix.mul(&genmatrix.mul(&genmatrix)))
     });
 
-    c.bench_function("matrix power", |b| b.iter(|| matrix_power(&genmatrix, 3)));
+    c.bench_function("matrix power", |b| b.iter(|| matrix_power(&genmatrix, 3.0)));
 
     c.bench_function("divergence hot loop", |b| {
         b.iter(|| {
-            let (t0, t1, t2) = (p[0] as i8, p[1] as i8, p[2] as i8);
+            let (t0, t1, t2) = (p[0], p[1], p[2]);
 
             // 			Define state vectors for t1,t2 and t0 from pedigree using matrix multiplications from library(expm)
-            let svt0 = sv_gzero.t().dot(&matrix_power(&genmatrix, t0));
+            let svt0 = sv_gzero.t().dot(&matrix_power(&genmatrix, t0).unwrap());
 
-            let t1t0 = matrix_power(&genmatrix, t1 - t0);
-            let t2t0 = matrix_power(&genmatrix, t2 - t0);
+            let t1t0 = matrix_power(&genmatrix, t1 - t0).unwrap();
+            let t2t0 = matrix_power(&genmatrix, t2 - t0).unwrap();
 
             let svt1_mm = t1t0.row(2);
             let svt2_mm = t2t0.row(2);
@@ -71,6 +71,11 @@ fn criterion_benchmark(c: &mut Criterion) {
         })
     });
 
+    let times = black_box(time_spans(&pedigree));
+    c.bench_function("cached matrix powers", |b| {
+        b.iter(|| matrix_powers(&genmatrix, &times))
+    });
+
     c.bench_function("divergence", |b| {
         b.iter(|| {
             divergence(
@@ -1,131 +1,141 @@
-use std::sync::Mutex;
-
 use crate::{
-    divergence::divergence,
+    diagnostics::{Diagnostics, Start},
+    optimizer::{Minimum, OptimizerSettings},
     pedigree::Pedigree,
-    structs::{Model, PredictedDivergence, Problem, Progress, Residuals},
+    structs::{
+        iteration_rng, Estimator, MatingSystem, Model, ModelKind, PredictedDivergence, Problem,
+        Progress, Residuals,
+    },
     *,
 };
-use argmin::{core::Executor, solver::neldermead::NelderMead};
 use indicatif::ProgressBar;
 use rayon::prelude::*;
 
+// The number of arguments is not ideal but it is just a complex function
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     pedigree: &Pedigree,
     p0uu: f64,
     eqp: f64,
     eqp_weight: f64,
+    mating: MatingSystem,
+    estimator: Estimator,
+    n_starts: usize,
+    seed: u64,
+    optimizer: OptimizerSettings,
+    pb: Option<&ProgressBar>,
+) -> Result<(Model, PredictedDivergence, Residuals, Diagnostics), Box<dyn std::error::Error>> {
+    let problem = Problem::new(
+        pedigree.clone(),
+        p0uu,
+        eqp,
+        eqp_weight,
+        ModelKind::Neutral,
+        mating,
+        estimator,
+    );
+    estimate(&problem, n_starts, seed, optimizer, pb)
+}
+
+/// Fit the model of `problem` from `n_starts` random starting points and keep the one with the lowest (weighted) least squares, or the highest likelihood.
+///
+/// Shared by all models, the public entry points only set up the `Problem`.
+/// The starting points only depend on `seed`, so the same seed gives the same model.
+pub fn estimate(
+    problem: &Problem,
     n_starts: usize,
+    seed: u64,
+    optimizer: OptimizerSettings,
     pb: Option<&ProgressBar>,
-) -> Result<(Model, PredictedDivergence, Residuals), Box<dyn std::error::Error>> {
-    let alternative_pb = Progress::new("ABneutral", n_starts).0;
+) -> Result<(Model, PredictedDivergence, Residuals, Diagnostics), Box<dyn std::error::Error>> {
+    let alternative_pb = Progress::new(problem.kind.name(), n_starts).0;
     let pb = pb.unwrap_or(&alternative_pb);
-    let p0mm = 1.0 - p0uu;
-    let p0um = 0.0;
+    let pedigree = &problem.pedigree;
     let max_divergence = *pedigree
         .column(3)
         .iter()
         .max_by(|a, b| a.partial_cmp(b).unwrap())
         .unwrap();
 
-    assert_eq!(p0mm + p0uu + p0um, 1.0);
+    // Every model draws its starting values from its own stream
+    let stream = problem.kind as u64;
+
+    // Fail early on times the model can't handle, instead of in every optimization
+    let mut rng = iteration_rng(seed, stream, 0);
+    if let Err(e @ Error::InvalidTime(_)) =
+        problem.divergence(&Model::new_for(problem.kind, max_divergence, &mut rng))
+    {
+        return Err(e.into());
+    }
+    if problem.estimator.needs_sites() && pedigree.sites().is_none() {
+        return Err(Error::Simple(
+            "The estimator needs the number of compared sites of every pair, which only pedigrees built from methylomes have",
+        )
+        .into());
+    }
+
+    problem.fixed.validate(problem.kind)?;
 
-    let results: Mutex<Vec<Model>> = Mutex::new(Vec::new());
     // let counter = AtomicU32::new(0);
 
     // Optimization loop
-    (0..n_starts).into_par_iter().for_each(|_| {
-        // Draw random starting values
-
-        let problem = Problem {
-            pedigree: pedigree.clone(),
-            eqp_weight,
-            eqp,
-            p_mm: p0mm,
-            p_um: p0um,
-            p_uu: p0uu,
-        };
-        // Run Nelder-Mead optimization
-        let nm = NelderMead::new(vec![
-            Model::new(max_divergence).to_vec(),
-            Model::new(max_divergence).to_vec(),
-            Model::new(max_divergence).to_vec(),
-            Model::new(max_divergence).to_vec(),
-            Model::new(max_divergence).to_vec(),
-        ]);
-
-        let res = Executor::new(problem, nm)
-            .configure(|state| {
-                state
-                    // .param(vec![alpha, beta, weight, intercept])
-                    .max_iters(10000)
-            })
-            .run()
-            .expect("Failed to run Nelder-Mead optimization");
-
-        let m = Model::from_vec(&res.state.best_param.unwrap());
-
-        // let predicted_mm = (m.alpha * ((1.0 - m.alpha).powi(2) - (1.0 - m.beta).powi(2) - 1.0))
-        //     / ((m.alpha + m.beta) * ((m.alpha + m.beta - 1.0).powi(2) - 2.0));
-        // let predicted_um = (4.0 * m.alpha * m.beta * (m.alpha + m.beta - 2.0))
-        //     / ((m.alpha + m.beta) * ((m.alpha + m.beta - 1.0).powi(2) - 2.0));
-        // let predicted_uu = (m.beta * ((1.0 - m.beta).powi(2) - (1.0 - m.alpha).powi(2) - 1.0))
-        //     / ((m.alpha + m.beta) * ((m.alpha + m.beta - 1.0).powi(2) - 2.0));
-        results.lock().unwrap().push(m);
-        // let c = counter.fetch_add(1, Ordering::SeqCst);
-        pb.inc(1);
-        //  println!("Progress: {}%", ((c * 100) as f32 / (n_starts) as f32));
-    });
+    let results: Vec<(Model, Minimum)> = (0..n_starts)
+        .into_par_iter()
+        .map(|i| {
+            // Draw random starting values
+            let mut rng = iteration_rng(seed, stream, i);
+            let simplex = (0..=problem.n_params())
+                .map(|_| problem.to_params(&Model::new_for(problem.kind, max_divergence, &mut rng)))
+                .collect();
+
+            let minimum = optimizer
+                .minimize(problem, simplex, 10000)
+                .expect("Failed to run optimization");
+
+            let m = problem.to_model(&minimum.param);
+
+            // let c = counter.fetch_add(1, Ordering::SeqCst);
+            pb.inc(1);
+            //  println!("Progress: {}%", ((c * 100) as f32 / (n_starts) as f32));
+            (m, minimum)
+        })
+        .collect();
     pb.finish();
 
-    let mut results = results.into_inner().unwrap();
-    // Calculating the least squares error for all results and selecting the best one
-    results.sort_by(|a, b| {
-        let pedigree = pedigree.clone();
-        let divergence_a = divergence(&pedigree, p0mm, p0um, p0uu, a.alpha, a.beta, a.weight);
-        let divergence_b = divergence(&pedigree, p0mm, p0um, p0uu, b.alpha, b.beta, b.weight);
-
-        let lse_a = divergence_a
-            .dt1t2
-            .iter()
-            .zip(pedigree.column(3))
-            .map(|(div, ped)| (ped - a.intercept - div).powi(2))
-            .sum::<f64>();
-        let lse_b = divergence_b
-            .dt1t2
-            .iter()
-            .zip(pedigree.column(3))
-            .map(|(div, ped)| (ped - b.intercept - div).powi(2))
-            .sum::<f64>();
-        lse_a.partial_cmp(&lse_b).unwrap() // Sort ascending
-    });
+    // Calculating the least squares error (or negative log-likelihood) for all results and selecting the best one
+    let starts = results
+        .into_iter()
+        .map(|(m, minimum)| {
+            let lse = problem.lack_of_fit(&m, &problem.divergence(&m)?.dt1t2)?;
+            Ok(Start::new(m, lse, minimum))
+        })
+        .collect::<Result<Vec<Start>, Error>>()?;
+    // Sorted ascending
+    let mut diagnostics = Diagnostics::new(problem, starts);
+    diagnostics.warn();
 
     // Calculting the predicted values based on the 'best' model (i.e. that with the lowest least square)
     // Caution: Calculating predicted divergence based on lowest LSQ model: check the biology!", "\n")
 
-    let best: &Model = &results[0];
-
-    let divergence = divergence(
-        pedigree,
-        p0mm,
-        p0um,
-        p0uu,
-        best.alpha,
-        best.beta,
-        best.weight,
-    );
-
-    let mut delta_t = Vec::new();
-    for row in pedigree.rows() {
-        delta_t.push(row[2] - row[3] - 2.0 * row[1])
+    let best: &mut Model = &mut diagnostics.starts[0].model;
+    best.on_boundary = problem.is_on_boundary(best);
+    if best.on_boundary {
+        println!(
+            "Warning: The best {} fit lies on the boundary of the parameter space: {best}",
+            problem.kind
+        );
+    }
+    let best: &Model = best;
+    if let Some(rho) = problem.overdispersion(best)? {
+        println!("Overdispersion of the beta-binomial: {rho}");
     }
 
+    let divergence = problem.divergence(best)?;
+
     let mut predicted_divergence = Vec::new();
 
     for (i, _row) in pedigree.rows().into_iter().enumerate() {
-        predicted_divergence.push(
-            best.intercept + divergence.dt1t2[i], /*+ delta_t[i] * best.alpha*/
-        );
+        predicted_divergence.push(best.intercept + divergence.dt1t2[i]);
     }
 
     let mut residuals = Vec::new();
@@ -134,11 +144,13 @@ pub fn run(
         residuals.push(row[3] - predicted_divergence[i]);
     }
 
-    // Generating theoretical fit
-
-    // Not needed for now
-
-    Ok((best.to_owned(), predicted_divergence, residuals))
+    // The theoretical fit is part of the goodness of fit, see `goodness_of_fit::GoodnessOfFit`
+    Ok((
+        best.to_owned(),
+        predicted_divergence,
+        residuals,
+        diagnostics,
+    ))
 }
 
 #[cfg(test)]
@@ -158,4 +170,71 @@ mod tests {
     //         assert_close!(result.weight, r.weight);
     //         assert_close!(result.intercept, r.intercept);
     //     }
+
+    use crate::structs::Fixed;
+
+    use super::*;
+
+    #[test]
+    fn same_seed_same_model() {
+        let (pedigree, p0uu, _) = Pedigree::example();
+        let fit = |seed| {
+            run(
+                &pedigree,
+                p0uu,
+                p0uu,
+                1.0,
+                MatingSystem::Selfing,
+                Estimator::LeastSquares,
+                2,
+                seed,
+                OptimizerSettings::default(),
+                None,
+            )
+            .expect("Model failed")
+        };
+        let (a, pred_a, _, _) = fit(42);
+        let (b, pred_b, _, _) = fit(42);
+        assert_eq!(a.to_vec(), b.to_vec());
+        assert_eq!(pred_a, pred_b);
+    }
+
+    #[test]
+    fn fixed_parameters_stay_fixed() {
+        let (pedigree, p0uu, _) = Pedigree::example();
+        let problem = Problem {
+            fixed: Fixed {
+                weight: Some(0.0),
+                intercept: Some(0.01),
+                ..Fixed::default()
+            },
+            ..Problem::new(
+                pedigree,
+                p0uu,
+                p0uu,
+                1.0,
+                ModelKind::Neutral,
+                MatingSystem::Selfing,
+                Estimator::LeastSquares,
+            )
+        };
+        assert_eq!(problem.n_params(), 2);
+
+        let (model, _, _, _) =
+            estimate(&problem, 2, 0, OptimizerSettings::default(), None).expect("Model failed");
+        assert_eq!(model.weight, 0.0);
+        assert_eq!(model.intercept, 0.01);
+        // A weight of zero is where it was put, not where the optimizer ran into
+        assert_eq!(model.on_boundary, model.alpha < 1e-10 || model.beta < 1e-10);
+
+        let all_fixed = Problem {
+            fixed: Fixed {
+                alpha: Some(0.01),
+                beta: Some(0.01),
+                ..problem.fixed
+            },
+            ..problem.clone()
+        };
+        assert!(estimate(&all_fixed, 2, 0, OptimizerSettings::default(), None).is_err());
+    }
 }
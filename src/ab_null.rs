@@ -0,0 +1,82 @@
+use crate::{
+    pedigree::Pedigree,
+    structs::{Model, PredictedDivergence, Residuals},
+    *,
+};
+
+/// Run ABnull: The "no epimutation" model, in which the divergence of every pair is explained by the intercept alone.
+///
+/// The least squares fit of a constant is the mean divergence, so no optimization is needed.
+/// The returned model has zero rates and weight.
+pub fn run(pedigree: &Pedigree) -> (Model, PredictedDivergence, Residuals) {
+    at_intercept(pedigree, pedigree.column(3).mean().unwrap_or(0.0))
+}
+
+/// Run ABnull by maximum likelihood over the compared sites of every pair.
+///
+/// The binomial fit of a constant is the divergence pooled over the sites of all pairs, which the beta-binomial fit is close to.
+pub fn run_pooled(pedigree: &Pedigree) -> Result<(Model, PredictedDivergence, Residuals), Error> {
+    let Some(sites) = pedigree.sites() else {
+        return Err(Error::Simple(
+            "The likelihood of ABnull needs the number of compared sites of every pair, which only pedigrees built from methylomes have",
+        ));
+    };
+    let intercept = pedigree.column(3).dot(&sites) / sites.sum();
+    Ok(at_intercept(pedigree, intercept))
+}
+
+/// Evaluate ABnull at a given intercept, e.g. a fixed one, which leaves nothing to estimate
+pub fn at_intercept(
+    pedigree: &Pedigree,
+    intercept: f64,
+) -> (Model, PredictedDivergence, Residuals) {
+    let model = Model {
+        alpha: 0.0,
+        beta: 0.0,
+        weight: 0.0,
+        intercept,
+        sel_coef: None,
+        on_boundary: false,
+    };
+
+    let predicted_divergence = vec![intercept; pedigree.nrows()];
+    let residuals = pedigree.column(3).iter().map(|d| d - intercept).collect();
+
+    (model, predicted_divergence, residuals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intercept_is_mean_divergence() {
+        let pedigree = Pedigree::from_file("./data/pedigree.txt");
+        let (model, _, residuals) = run(&pedigree);
+
+        assert_eq!(model.intercept, pedigree.column(3).mean().unwrap());
+        crate::assert_close!(residuals.iter().sum::<f64>(), 0.0);
+    }
+
+    #[test]
+    fn fixed_intercept_is_kept() {
+        let pedigree = Pedigree::from_file("./data/pedigree.txt");
+        let (model, _, residuals) = at_intercept(&pedigree, 0.01);
+
+        assert_eq!(model.intercept, 0.01);
+        crate::assert_close!(residuals[0], pedigree[[0, 3]] - 0.01);
+    }
+
+    #[test]
+    fn pooled_intercept_weighs_pairs_by_sites() {
+        let mut pedigree = Pedigree::from_file("./data/pedigree.txt");
+        assert!(run_pooled(&pedigree).is_err());
+
+        let mut sites = ndarray::Array1::<f64>::ones(pedigree.nrows());
+        sites[0] = 1e9;
+        pedigree.push_column(sites.view()).unwrap();
+        let (model, _, _) = run_pooled(&pedigree).unwrap();
+
+        assert!((model.intercept - pedigree[[0, 3]]).abs() < 1e-6);
+    }
+}
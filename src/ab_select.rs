@@ -0,0 +1,128 @@
+use crate::{
+    ab_neutral::estimate,
+    diagnostics::Diagnostics,
+    optimizer::OptimizerSettings,
+    pedigree::Pedigree,
+    structs::{Estimator, MatingSystem, Model, ModelKind, PredictedDivergence, Problem, Residuals},
+};
+use indicatif::ProgressBar;
+
+/// Run ABselectMM: Like ABneutral, but with selection against the MM state.
+///
+/// The returned model carries the estimated selection coefficient.
+// The number of arguments is not ideal but it is just a complex function
+#[allow(clippy::too_many_arguments)]
+pub fn run_mm(
+    pedigree: &Pedigree,
+    p0uu: f64,
+    eqp: f64,
+    eqp_weight: f64,
+    mating: MatingSystem,
+    estimator: Estimator,
+    n_starts: usize,
+    seed: u64,
+    optimizer: OptimizerSettings,
+    pb: Option<&ProgressBar>,
+) -> Result<(Model, PredictedDivergence, Residuals, Diagnostics), Box<dyn std::error::Error>> {
+    run(
+        ModelKind::SelectMM,
+        pedigree,
+        p0uu,
+        eqp,
+        eqp_weight,
+        mating,
+        estimator,
+        n_starts,
+        seed,
+        optimizer,
+        pb,
+    )
+}
+
+/// Run ABselectUU: Like ABneutral, but with selection against the UU state.
+///
+/// The returned model carries the estimated selection coefficient.
+// The number of arguments is not ideal but it is just a complex function
+#[allow(clippy::too_many_arguments)]
+pub fn run_uu(
+    pedigree: &Pedigree,
+    p0uu: f64,
+    eqp: f64,
+    eqp_weight: f64,
+    mating: MatingSystem,
+    estimator: Estimator,
+    n_starts: usize,
+    seed: u64,
+    optimizer: OptimizerSettings,
+    pb: Option<&ProgressBar>,
+) -> Result<(Model, PredictedDivergence, Residuals, Diagnostics), Box<dyn std::error::Error>> {
+    run(
+        ModelKind::SelectUU,
+        pedigree,
+        p0uu,
+        eqp,
+        eqp_weight,
+        mating,
+        estimator,
+        n_starts,
+        seed,
+        optimizer,
+        pb,
+    )
+}
+
+// The number of arguments is not ideal but it is just a complex function
+#[allow(clippy::too_many_arguments)]
+fn run(
+    kind: ModelKind,
+    pedigree: &Pedigree,
+    p0uu: f64,
+    eqp: f64,
+    eqp_weight: f64,
+    mating: MatingSystem,
+    estimator: Estimator,
+    n_starts: usize,
+    seed: u64,
+    optimizer: OptimizerSettings,
+    pb: Option<&ProgressBar>,
+) -> Result<(Model, PredictedDivergence, Residuals, Diagnostics), Box<dyn std::error::Error>> {
+    let problem = Problem::new(
+        pedigree.clone(),
+        p0uu,
+        eqp,
+        eqp_weight,
+        kind,
+        mating,
+        estimator,
+    );
+    estimate(&problem, n_starts, seed, optimizer, pb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_selection_coefficient() {
+        let (pedigree, p0uu, _) = Pedigree::example();
+        let (model, pred_div, residuals, diagnostics) = run_mm(
+            &pedigree,
+            p0uu,
+            p0uu,
+            1.0,
+            MatingSystem::Selfing,
+            Estimator::LeastSquares,
+            2,
+            0,
+            OptimizerSettings::default(),
+            None,
+        )
+        .expect("Model failed");
+
+        assert!(model.sel_coef.is_some());
+        assert_eq!(pred_div.len(), pedigree.nrows());
+        assert_eq!(residuals.len(), pedigree.nrows());
+        assert_eq!(diagnostics.starts.len(), 2);
+        assert_eq!(diagnostics.starts[0].model.sel_coef, model.sel_coef);
+    }
+}
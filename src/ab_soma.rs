@@ -0,0 +1,72 @@
+use crate::{
+    ab_neutral::estimate,
+    diagnostics::Diagnostics,
+    optimizer::OptimizerSettings,
+    pedigree::Pedigree,
+    structs::{Estimator, MatingSystem, Model, ModelKind, PredictedDivergence, Problem, Residuals},
+};
+use indicatif::ProgressBar;
+
+/// Run ABsoma: Epimutations accumulating along somatic lineages, e.g. the branches of a tree.
+///
+/// The pedigree times are branch lengths in arbitrary units (e.g. years) and don't need to be whole numbers,
+/// the estimated rates are per unit of time.
+// The number of arguments is not ideal but it is just a complex function
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    pedigree: &Pedigree,
+    p0uu: f64,
+    eqp: f64,
+    eqp_weight: f64,
+    estimator: Estimator,
+    n_starts: usize,
+    seed: u64,
+    optimizer: OptimizerSettings,
+    pb: Option<&ProgressBar>,
+) -> Result<(Model, PredictedDivergence, Residuals, Diagnostics), Box<dyn std::error::Error>> {
+    // Somatic lineages don't mate
+    let problem = Problem::new(
+        pedigree.clone(),
+        p0uu,
+        eqp,
+        eqp_weight,
+        ModelKind::Soma,
+        MatingSystem::Selfing,
+        estimator,
+    );
+    estimate(&problem, n_starts, seed, optimizer, pb)
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::array;
+
+    use super::*;
+
+    #[test]
+    fn fits_fractional_times() {
+        let pedigree = Pedigree::from(array![
+            [0.0, 2.5, 2.5, 0.011],
+            [0.0, 7.25, 7.25, 0.024],
+            [1.5, 12.0, 9.75, 0.035],
+            [0.0, 20.0, 12.0, 0.048],
+            [4.0, 30.5, 30.5, 0.071],
+        ]);
+        let (model, pred_div, residuals, _) = run(
+            &pedigree,
+            0.75,
+            0.75,
+            1.0,
+            Estimator::LeastSquares,
+            2,
+            0,
+            OptimizerSettings::default(),
+            None,
+        )
+        .expect("Model failed");
+
+        assert!(model.sel_coef.is_none());
+        assert_eq!(pred_div.len(), pedigree.nrows());
+        assert!(residuals.iter().all(|r| r.is_finite()));
+    }
+}
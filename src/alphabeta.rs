@@ -1,15 +1,27 @@
 use anyhow::anyhow;
 use indicatif::MultiProgress;
+use ndarray_npy::write_npy;
+use rand::{thread_rng, Rng};
 
 use crate::{
-    analysis::{Analysis, RawAnalysis},
+    analysis::{Analysis, IntervalMethod, RawAnalysis},
     arguments::AlphaBeta as Args,
+    comparison::{Comparison, Fit},
+    cross_validation::EQP_WEIGHTS,
+    diagnostics::Diagnostics,
+    goodness_of_fit::GoodnessOfFit,
+    jackknife::Jackknife,
+    mcmc::Priors,
+    misclassification::Misclassification,
+    optimizer::{Optimizer, OptimizerSettings},
     pedigree::Pedigree,
+    profile::Profiled,
     progress::specific,
-    structs::Model,
+    structs::{Fixed, MatingSystem, Model, ModelKind, Problem},
     *,
 };
 
+type EstSteadyState = f64;
 type ObsSteadyState = f64;
 
 /// Run AlphaBeta
@@ -19,43 +31,244 @@ type ObsSteadyState = f64;
 /// * `Model` - The best model found by the algorithm
 /// * `Analysis` - The analysis of the model, done by bootstrapping
 /// * `Pedigree` - The pedigree used for the analysis
+/// * The steady state methylation level at the equilibrium of the best model
 /// * The observed steady state methylation level
+/// * `Comparison` - How the fitted model compares to ABnull (and ABneutral)
+/// * `Diagnostics` - How the starts of the estimation converged
+/// * `Jackknife` - The fits without each sample, if requested or needed for BCa intervals
+// All results are returned side by side, like the other run functions do
+#[allow(clippy::type_complexity)]
 pub fn run(
     args: Args,
     bars: &MultiProgress,
-) -> Result<(Model, Analysis, RawAnalysis, Pedigree, ObsSteadyState)> {
+) -> Result<(
+    Model,
+    Analysis,
+    RawAnalysis,
+    Pedigree,
+    EstSteadyState,
+    ObsSteadyState,
+    Comparison,
+    Diagnostics,
+    Option<Jackknife>,
+)> {
     println!("Building pedigree...");
-    let (pedigree, p0uu) = Pedigree::build(&args.nodes, &args.edges, args.posterior_max_filter)
-        .map_err(|e| anyhow!("Error while building pedigree: {}", e))?;
+    let (pedigree, p0uu, genealogy) = Pedigree::build_with_genealogy(
+        &args.nodes,
+        &args.edges,
+        args.posterior_max_filter,
+        args.divergence,
+    )
+    .map_err(|e| anyhow!("Error while building pedigree: {}", e))?;
+    let misclassification = Misclassification::new(&pedigree, &genealogy, args.error_model);
+    misclassification.to_file(&args.output.join("misclassification.txt"))?;
+    if let Some(share) = misclassification.share() {
+        println!(
+            "Miscalled sites are expected to make up {:.1}% of the observed divergence",
+            100.0 * share
+        );
+    }
+    let pedigree = misclassification.apply(&pedigree)?;
 
     let (pb_neutral, pb_boot) = specific(bars, args.iterations);
 
-    let (model, pred_div, residuals) = ab_neutral::run(
-        &pedigree,
-        p0uu,
-        p0uu,
-        1.0,
-        args.iterations,
-        Some(&pb_neutral),
-    )
-    .map_err(|e| anyhow!("Model failed: {}", e))?;
-    let (analysis, raw_analysis) = boot_model::run(
+    let seed = args.seed.unwrap_or_else(|| thread_rng().gen());
+    println!("Random seed: {seed} (pass it with --seed to reproduce this run)");
+    if args.optimizer == Optimizer::ParticleSwarm {
+        println!("Warning: The particle swarm draws its own random numbers in every iteration, so the seed does not reproduce its fits");
+    }
+
+    let optimizer = OptimizerSettings {
+        optimizer: args.optimizer,
+        max_iters: args.max_iters,
+        tolerance: args.tolerance,
+    };
+
+    let (est, n, opt) = (args.estimator, args.iterations, optimizer);
+    // The equilibrium penalty pulls towards the observed methylation level unless told otherwise
+    let eqp = args.eqp.unwrap_or(p0uu);
+    // Somatic lineages don't mate
+    let mating = match args.model {
+        ModelKind::Soma => MatingSystem::Selfing,
+        _ => args.mating,
+    };
+    let mut problem = Problem {
+        fixed: args.fixed(),
+        ..Problem::new(
+            pedigree.clone(),
+            p0uu,
+            eqp,
+            args.eqp_weight,
+            args.model,
+            mating,
+            est,
+        )
+    };
+    if args.cv_eqp_weight {
+        println!("Cross-validating the weight of the equilibrium penalty...");
+        let cv = cross_validation::eqp_weight(&problem, &EQP_WEIGHTS, args.cv_folds, n, seed, opt)
+            .map_err(|e| anyhow!("Cross-validation failed: {}", e))?;
+        cv.to_file(&args.output.join("cv_eqp_weight.txt"))?;
+        println!("Weight of the equilibrium penalty: {}", cv.best);
+        problem.eqp_weight = cv.best;
+    }
+    let eqp_weight = problem.eqp_weight;
+
+    let (model, pred_div, residuals, diagnostics) =
+        ab_neutral::estimate(&problem, n, seed, opt, Some(&pb_neutral))
+            .map_err(|e| anyhow!("Model failed: {}", e))?;
+    println!(
+        "The equilibrium penalty makes up {} of the cost of the best fit ({:.1}%)",
+        diagnostics.penalty,
+        100.0 * diagnostics.penalty_share()
+    );
+
+    let goodness_of_fit = GoodnessOfFit::new(&problem, &model, &pred_div, &residuals)
+        .map_err(|e| anyhow!("Goodness of fit failed: {}", e))?;
+    goodness_of_fit.to_file(&args.output.join("goodness_of_fit.txt"))?;
+    plot::fit(&goodness_of_fit, &args.output)?;
+    println!("R squared of the best fit: {}", goodness_of_fit.r_squared);
+
+    // Fits by maximum likelihood are compared by their likelihood, the others by their residual sum of squares
+    // A fixed intercept is fixed for ABnull as well, which then estimates nothing
+    let (null_model, _, null_residuals) = match problem.fixed.intercept {
+        Some(intercept) => ab_null::at_intercept(&pedigree, intercept),
+        None if est.is_likelihood() => ab_null::run_pooled(&pedigree)?,
+        None => ab_null::run(&pedigree),
+    };
+    let null_params = usize::from(problem.fixed.intercept.is_none());
+    let null_predicted = vec![0.0; pedigree.nrows()];
+    let null = Fit::of(
+        "ABnull",
+        null_params,
+        &problem,
+        &null_model,
+        &null_predicted,
+        &null_residuals,
+    )?;
+    // Fixed parameters are not estimated and don't count
+    let predicted = problem.divergence(&model)?.dt1t2;
+    let fit = Fit::of(
+        args.model.name(),
+        problem.n_params(),
+        &problem,
+        &model,
+        &predicted,
+        &residuals,
+    )?;
+    let comparison = if args.model.has_selection() {
+        // The selection models are compared to ABneutral, which therefore has to be fitted as well
+        let neutral = Problem {
+            kind: ModelKind::Neutral,
+            fixed: Fixed {
+                sel_coef: None,
+                ..problem.fixed
+            },
+            ..problem.clone()
+        };
+        let (neutral_model, _, neutral_residuals, _) =
+            ab_neutral::estimate(&neutral, n, seed, opt, None)
+                .map_err(|e| anyhow!("Model failed: {}", e))?;
+        let neutral_predicted = neutral.divergence(&neutral_model)?.dt1t2;
+        let neutral = Fit::of(
+            neutral.kind.name(),
+            neutral.n_params(),
+            &neutral,
+            &neutral_model,
+            &neutral_predicted,
+            &neutral_residuals,
+        )?;
+        Comparison::new(null, neutral, Some(fit))
+    } else {
+        Comparison::new(null, fit, None)
+    };
+
+    // BCa intervals take their acceleration from the jackknife
+    let jackknife = if args.jackknife || args.ci_method == IntervalMethod::BCa {
+        println!("Refitting without each sample...");
+        let jackknife = jackknife::run(&problem, &model, &genealogy, args.eqp, n, seed, opt, None)
+            .map_err(|e| anyhow!("Jackknife failed: {}", e))?;
+        Some(jackknife)
+    } else {
+        None
+    };
+
+    let (mut analysis, raw_analysis) = boot_model::run(
         &pedigree,
         &model,
+        args.model,
+        problem.mating,
+        args.estimator,
+        problem.fixed,
         pred_div,
         residuals,
+        args.bootstrap,
+        Some(&genealogy),
+        args.ci_level,
+        args.ci_method,
+        jackknife.as_ref(),
         p0uu,
-        p0uu,
-        1.0,
+        eqp,
+        eqp_weight,
         args.iterations,
+        seed,
+        optimizer,
         Some(&pb_boot),
         &args.output,
     )
     .map_err(|e| anyhow!("Bootstrap failed: {}", e))?;
+
+    if args.profile {
+        println!("Profiling alpha and beta...");
+        for parameter in [Profiled::Alpha, Profiled::Beta] {
+            if parameter.is_fixed(&problem.fixed) {
+                continue;
+            }
+            let profile = profile::run(&problem, &model, parameter, args.ci_level, seed, opt)
+                .map_err(|e| anyhow!("Profile failed: {}", e))?;
+            let file = format!("profile_{}.txt", parameter.name().to_lowercase());
+            profile.to_file(&args.output.join(file))?;
+            match parameter {
+                Profiled::Alpha => analysis.profile_ci_alpha = Some(profile.ci),
+                Profiled::Beta => analysis.profile_ci_beta = Some(profile.ci),
+            }
+        }
+    }
+    if args.mcmc {
+        println!("Sampling the posterior...");
+        let priors = Priors {
+            alpha: args.prior_alpha,
+            beta: args.prior_beta,
+        };
+        let posterior = mcmc::run(
+            &problem,
+            &model,
+            priors,
+            args.chains,
+            args.draws,
+            args.ci_level,
+            seed,
+        )
+        .map_err(|e| anyhow!("Sampler failed: {}", e))?;
+        posterior.warn();
+        posterior.to_file(&args.output.join("posterior.txt"))?;
+        write_npy(args.output.join("posterior.npy"), &posterior.draws.0)?;
+    }
     bars.remove(&pb_neutral);
     bars.remove(&pb_boot);
 
-    Ok((model, analysis, raw_analysis, pedigree, 1.0 - p0uu))
+    let est_steady_state = problem.steady_state(&model);
+    Ok((
+        model,
+        analysis,
+        raw_analysis,
+        pedigree,
+        est_steady_state,
+        1.0 - p0uu,
+        comparison,
+        diagnostics,
+        jackknife,
+    ))
 }
 
 /// Calculate the steady state UU level
@@ -70,7 +283,7 @@ pub fn p_mm_est(alpha: f64, beta: f64) -> f64 {
         / ((alpha + beta) * ((alpha + beta - 1.0).powi(2) - 2.0))
 }
 
-/// Calculate the steady state methylation level
+/// Calculate the steady state methylation level of ABneutral with selfing, see [`Problem::steady_state`] for the other models
 pub fn steady_state(alpha: f64, beta: f64) -> f64 {
     let pi_2 = (4.0 * alpha * beta * (alpha + beta - 2.0))
         / ((alpha + beta) * ((alpha + beta - 1.0).powi(2) - 2.0));
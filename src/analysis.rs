@@ -1,14 +1,19 @@
 use std::{path::Path, fs::File, fmt::Display, ops::Div, io::Write};
 
-use ndarray::{Array2, ArrayView1, array};
+use ndarray::{Array1, Array2, ArrayView1, array};
 use ndarray_stats::{interpolate::Linear, Quantile1dExt};
 use noisy_float::types::n64;
+use statrs::distribution::{ContinuousCDF, Normal};
+
+use crate::structs::{BootstrapMode, Estimator, Fixed, MatingSystem};
 
 /// 2D-Array containing the results of all the iterations of the bootstrapping analysis.
 /// 
 /// Dimensions: 7 x n_bootze
 /// 
 /// Columns: Alpha, Beta, Weight, Intercept, Pr(MM), Pr(UM), Pr(UU)
+///
+/// The selection models add an eighth column with the selection coefficient.
 pub struct RawAnalysis(pub Array2<f64>);
 
 
@@ -44,24 +49,95 @@ pub struct Analysis {
     pub ci_pr_mm: CI,
     pub ci_pr_um: CI,
     pub ci_pr_uu: CI,
+
+    /// Geometric means of the rates, and the standard deviations and intervals of their log10.
+    /// The rates span orders of magnitude, where the arithmetic statistics are dominated by the largest values
+    pub gm_alpha: f64,
+    pub gm_beta: f64,
+    pub gm_alphabeta: f64,
+
+    pub log_sd_alpha: f64,
+    pub log_sd_beta: f64,
+    pub log_sd_alphabeta: f64,
+
+    pub log_ci_alpha: CI,
+    pub log_ci_beta: CI,
+    pub log_ci_alphabeta: CI,
+
+    /// Only present for the selection models
+    pub sel_coef: Option<f64>,
+    pub sd_sel_coef: Option<f64>,
+    pub ci_sel_coef: Option<CI>,
+
+    /// Mating system the model was fitted with
+    pub mating: MatingSystem,
+    /// How the bootstrap data sets were generated
+    pub bootstrap: BootstrapMode,
+    /// What the fits minimized
+    pub estimator: Estimator,
+    /// Parameters that were not estimated, reported as fixed instead of with an SD and interval
+    pub fixed: Fixed,
+
+    /// Confidence level of the intervals, e.g. 0.95
+    pub level: f64,
+    pub method: IntervalMethod,
+
+    /// Profile intervals of the rates at the same level, if requested
+    pub profile_ci_alpha: Option<CI>,
+    pub profile_ci_beta: Option<CI>,
+}
+
+/// How the confidence intervals are computed from the bootstrap estimates
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum IntervalMethod {
+    /// Quantiles of the bootstrap estimates
+    #[default]
+    Percentile,
+    /// Bias-corrected and accelerated: Quantiles shifted by the bias of the bootstrap estimates and by the skewness of a jackknife
+    #[value(name = "bca")]
+    BCa,
+}
+
+impl Display for IntervalMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Percentile => "Percentile",
+            Self::BCa => "BCa",
+        };
+        write!(f, "{name}")
+    }
 }
 
 impl RawAnalysis{
+  /// Summarize the bootstrap with 95% percentile intervals
   pub  fn analyze(&self) -> Analysis {
-        
-    
+    self.analyze_with(0.95, None)
+  }
+
+  /// Summarize the bootstrap with confidence intervals of the given `level`.
+  ///
+  /// With the estimates of the original fit and of a jackknife, both in the column layout of the bootstrap, the intervals are BCa, otherwise percentile intervals.
+  pub fn analyze_with(&self, level: f64, bca: Option<(&[f64], &Array2<f64>)>) -> Analysis {
     let results = self.0.view();
     let alphabeta = results.column(1).div(&results.column(0));
 
-    let ci = |val: ArrayView1<f64>| {
-        let q = val
-            .map(|x| n64(*x))
-            .to_owned()
-            .quantiles_mut(&array![n64(0.025), n64(0.975)], &Linear)
-            .unwrap();
+    let values = |column: usize| if column == ALPHABETA { alphabeta.view() } else { results.column(column) };
+    let bca_of = |column: usize| bca.map(|(original, jackknife)| {
+        if column == ALPHABETA {
+            (original[1] / original[0], jackknife.column(1).div(&jackknife.column(0)))
+        } else {
+            (original[column], jackknife.column(column).to_owned())
+        }
+    });
+    let ci = |column: usize| interval(values(column), level, bca_of(column));
 
-        CI(q[0].raw(), q[1].raw())
+    // The same on the log10 scale, which the percentile intervals are invariant to but the BCa acceleration is not
+    let log10 = |column: usize| values(column).mapv(f64::log10);
+    let log_ci = |column: usize| {
+        let bca = bca_of(column).map(|(original, jackknife)| (original.log10(), jackknife.mapv(f64::log10)));
+        interval(log10(column).view(), level, bca)
     };
+    let gm = |column: usize| 10.0_f64.powf(log10(column).mean().unwrap());
 
    Analysis {
         alpha: results.column(0).mean().unwrap(),
@@ -85,60 +161,90 @@ impl RawAnalysis{
         sd_pr_um: results.column(5).std(1.0),
         sd_pr_uu: results.column(6).std(1.0),
 
-        ci_alpha: ci(results.column(0)),
-        ci_beta: ci(results.column(1)),
-        ci_alphabeta: ci(alphabeta.view()),
-        ci_weight: ci(results.column(2)),
-        ci_intercept: ci(results.column(3)),
+        ci_alpha: ci(0),
+        ci_beta: ci(1),
+        ci_alphabeta: ci(ALPHABETA),
+        ci_weight: ci(2),
+        ci_intercept: ci(3),
+
+        ci_pr_mm: ci(4),
+        ci_pr_um: ci(5),
+        ci_pr_uu: ci(6),
 
-        ci_pr_mm: ci(results.column(4)),
-        ci_pr_um: ci(results.column(5)),
-        ci_pr_uu: ci(results.column(6)),
+        gm_alpha: gm(0),
+        gm_beta: gm(1),
+        gm_alphabeta: gm(ALPHABETA),
+
+        log_sd_alpha: log10(0).std(1.0),
+        log_sd_beta: log10(1).std(1.0),
+        log_sd_alphabeta: log10(ALPHABETA).std(1.0),
+
+        log_ci_alpha: log_ci(0),
+        log_ci_beta: log_ci(1),
+        log_ci_alphabeta: log_ci(ALPHABETA),
+
+        sel_coef: (results.ncols() > 7).then(|| results.column(7).mean().unwrap()),
+        sd_sel_coef: (results.ncols() > 7).then(|| results.column(7).std(1.0)),
+        ci_sel_coef: (results.ncols() > 7).then(|| ci(7)),
+
+        mating: MatingSystem::default(),
+        bootstrap: BootstrapMode::default(),
+        estimator: Estimator::default(),
+        fixed: Fixed::default(),
+        level,
+        method: if bca.is_some() { IntervalMethod::BCa } else { IntervalMethod::Percentile },
+        profile_ci_alpha: None,
+        profile_ci_beta: None,
     }
     }
 }
 
+/// Stand-in column index of Beta/Alpha, which is not stored in the raw analysis
+const ALPHABETA: usize = usize::MAX;
+
+/// Confidence interval of the bootstrap estimates `values` at `level`, or credible interval of posterior draws.
+///
+/// With the original estimate and its jackknife estimates, the quantiles are BCa-adjusted:
+/// The bias correction z0 is the normal quantile of the share of bootstrap estimates below the original one,
+/// the acceleration the skewness of the jackknife estimates (Efron & Tibshirani, 1993, ch. 14.3).
+pub(crate) fn interval(values: ArrayView1<f64>, level: f64, bca: Option<(f64, Array1<f64>)>) -> CI {
+    let tail = (1.0 - level) / 2.0;
+    let (lower, upper) = match bca {
+        None => (tail, 1.0 - tail),
+        Some((original, jackknife)) => {
+            let normal = Normal::new(0.0, 1.0).unwrap();
+            let n = values.len() as f64;
+            let below = values.iter().filter(|v| **v < original).count() as f64
+                + 0.5 * values.iter().filter(|v| **v == original).count() as f64;
+            // All estimates on one side of the original one would make the correction infinite
+            let z0 = normal.inverse_cdf((below / n).clamp(0.5 / n, 1.0 - 0.5 / n));
+
+            let mean = jackknife.mean().unwrap_or(0.0);
+            let squares = jackknife.iter().map(|j| (mean - j).powi(2)).sum::<f64>();
+            let cubes = jackknife.iter().map(|j| (mean - j).powi(3)).sum::<f64>();
+            let acceleration = if squares > 0.0 { cubes / (6.0 * squares.powf(1.5)) } else { 0.0 };
+
+            let adjust = |q: f64| {
+                let z = z0 + normal.inverse_cdf(q);
+                normal.cdf(z0 + z / (1.0 - acceleration * z))
+            };
+            (adjust(tail), adjust(1.0 - tail))
+        }
+    };
+
+    let q = values
+        .map(|x| n64(*x))
+        .quantiles_mut(&array![n64(lower), n64(upper)], &Linear)
+        .unwrap();
+
+    CI(q[0].raw(), q[1].raw())
+}
+
 impl Analysis {
     pub fn to_file(&self, path: &Path) -> std::io::Result<()> {
         println!("Writing model to file: {}", path.display());
         let mut file = File::create(path).unwrap();
-        let  content = format!(
-            "Alpha\t{}\nBeta\t{}\nAlphaBeta\t{}\nWeight\t{}\nIntercept\t{}\nPrMM\t{}\nPrUM\t{}\nPrUU\t{}\nSDAlpha\t{}\nSDBeta\t{}\nSDAlphaBeta\t{}\nSDWeight\t{}\nSDIntercept\t{}\nSDPrMM\t{}\nSDPrUM\t{}\nSDPrUU\t{}\nCIAlpha\t{}-{}\nCIBeta\t{}-{}\nCIAlphaBeta\t{}-{}\nCIWeight\t{}-{}\nCIIntercept\t{}-{}\nCIPrMM\t{}-{}\nCIPrUM\t{}-{}\nCIPrUU\t{}-{}\n",
-            self.alpha,
-            self.beta,
-            self.alphabeta,
-            self.weight,
-            self.intercept,
-            self.pr_mm,
-            self.pr_um,
-            self.pr_uu,
-            self.sd_alpha,
-            self.sd_beta,
-            self.sd_alphabeta,
-            self.sd_weight,
-            self.sd_intercept,
-            self.sd_pr_mm,
-            self.sd_pr_um,
-            self.sd_pr_uu,
-            self.ci_alpha.0,
-            self.ci_alpha.1,
-            self.ci_beta.0,
-            self.ci_beta.1,
-            self.ci_alphabeta.0,
-            self.ci_alphabeta.1,
-            self.ci_weight.0,
-            self.ci_weight.1,
-            self.ci_intercept.0,
-            self.ci_intercept.1,
-            self.ci_pr_mm.0,
-            self.ci_pr_mm.1,
-            self.ci_pr_um.0,
-            self.ci_pr_um.1,
-            self.ci_pr_uu.0,
-            self.ci_pr_uu.1,
-            
-
-        );
+        let content = self.to_string();
 
         file.write_all(content.as_bytes())
     }
@@ -146,8 +252,13 @@ impl Analysis {
 
 impl Display   for Analysis {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Fixed parameters were not estimated, so they have no spread to report
+        let alphabeta_fixed = self.fixed.alpha.is_some() && self.fixed.beta.is_some();
+        let sd = |fixed: Option<f64>, sd: f64| if fixed.is_some() { "fixed".to_string() } else { sd.to_string() };
+        let ci = |fixed: Option<f64>, ci: &CI| if fixed.is_some() { "fixed".to_string() } else { format!("{}-{}", ci.0, ci.1) };
+        let ab = alphabeta_fixed.then_some(self.alphabeta);
         write!(f, 
-            "Alpha\t{}\nBeta\t{}\nAlphaBeta\t{}\nWeight\t{}\nIntercept\t{}\nPrMM\t{}\nPrUM\t{}\nPrUU\t{}\nSDAlpha\t{}\nSDBeta\t{}\nSDAlphaBeta\t{}\nSDWeight\t{}\nSDIntercept\t{}\nSDPrMM\t{}\nSDPrUM\t{}\nSDPrUU\t{}\nCIAlpha\t{}-{}\nCIBeta\t{}-{}\nCIAlphaBeta\t{}-{}\nCIWeight\t{}-{}\nCIIntercept\t{}-{}\nCIPrMM\t{}-{}\nCIPrUM\t{}-{}\nCIPrUU\t{}-{}\n",
+            "Alpha\t{}\nBeta\t{}\nAlphaBeta\t{}\nWeight\t{}\nIntercept\t{}\nPrMM\t{}\nPrUM\t{}\nPrUU\t{}\nSDAlpha\t{}\nSDBeta\t{}\nSDAlphaBeta\t{}\nSDWeight\t{}\nSDIntercept\t{}\nSDPrMM\t{}\nSDPrUM\t{}\nSDPrUU\t{}\nCIAlpha\t{}\nCIBeta\t{}\nCIAlphaBeta\t{}\nCIWeight\t{}\nCIIntercept\t{}\nCIPrMM\t{}-{}\nCIPrUM\t{}-{}\nCIPrUU\t{}-{}\n",
             self.alpha,
             self.beta,
             self.alphabeta,
@@ -156,24 +267,19 @@ impl Display   for Analysis {
             self.pr_mm,
             self.pr_um,
             self.pr_uu,
-            self.sd_alpha,
-            self.sd_beta,
-            self.sd_alphabeta,
-            self.sd_weight,
-            self.sd_intercept,
+            sd(self.fixed.alpha, self.sd_alpha),
+            sd(self.fixed.beta, self.sd_beta),
+            sd(ab, self.sd_alphabeta),
+            sd(self.fixed.weight, self.sd_weight),
+            sd(self.fixed.intercept, self.sd_intercept),
             self.sd_pr_mm,
             self.sd_pr_um,
             self.sd_pr_uu,
-            self.ci_alpha.0,
-            self.ci_alpha.1,
-            self.ci_beta.0,
-            self.ci_beta.1,
-            self.ci_alphabeta.0,
-            self.ci_alphabeta.1,
-            self.ci_weight.0,
-            self.ci_weight.1,
-            self.ci_intercept.0,
-            self.ci_intercept.1,
+            ci(self.fixed.alpha, &self.ci_alpha),
+            ci(self.fixed.beta, &self.ci_beta),
+            ci(ab, &self.ci_alphabeta),
+            ci(self.fixed.weight, &self.ci_weight),
+            ci(self.fixed.intercept, &self.ci_intercept),
             self.ci_pr_mm.0,
             self.ci_pr_mm.1,
             self.ci_pr_um.0,
@@ -182,7 +288,38 @@ impl Display   for Analysis {
             self.ci_pr_uu.1,
             
 
-        )
+        )?;
+        write!(f,
+            "GMAlpha\t{}\nGMBeta\t{}\nGMAlphaBeta\t{}\nLog10SDAlpha\t{}\nLog10SDBeta\t{}\nLog10SDAlphaBeta\t{}\nLog10CIAlpha\t{}\nLog10CIBeta\t{}\nLog10CIAlphaBeta\t{}\n",
+            self.gm_alpha,
+            self.gm_beta,
+            self.gm_alphabeta,
+            sd(self.fixed.alpha, self.log_sd_alpha),
+            sd(self.fixed.beta, self.log_sd_beta),
+            sd(ab, self.log_sd_alphabeta),
+            ci(self.fixed.alpha, &self.log_ci_alpha),
+            ci(self.fixed.beta, &self.log_ci_beta),
+            ci(ab, &self.log_ci_alphabeta),
+        )?;
+        if let (Some(sel_coef), Some(sd_sel_coef), Some(ci_sel_coef)) = (self.sel_coef, self.sd_sel_coef, &self.ci_sel_coef) {
+            write!(f, "SelCoef\t{}\nSDSelCoef\t{}\nCISelCoef\t{}\n", sel_coef, sd(self.fixed.sel_coef, sd_sel_coef), ci(self.fixed.sel_coef, ci_sel_coef))?;
+        }
+        writeln!(f, "Mating\t{}", self.mating)?;
+        writeln!(f, "Bootstrap\t{}", self.bootstrap)?;
+        writeln!(f, "Estimator\t{}", self.estimator)?;
+        if self.fixed != Fixed::default() {
+            writeln!(f, "Fixed\t{}", self.fixed)?;
+        }
+        writeln!(f, "CILevel\t{}", self.level)?;
+        writeln!(f, "CIMethod\t{}", self.method)?;
+        // A fixed rate is not profiled, the other one still is
+        if let Some(alpha) = &self.profile_ci_alpha {
+            writeln!(f, "ProfileCIAlpha\t{}-{}", alpha.0, alpha.1)?;
+        }
+        if let Some(beta) = &self.profile_ci_beta {
+            writeln!(f, "ProfileCIBeta\t{}-{}", beta.0, beta.1)?;
+        }
+        Ok(())
     }
 }
 
@@ -194,3 +331,79 @@ impl Display   for Analysis {
 #[derive(Debug, Clone)]
 pub struct CI(pub f64, pub f64);
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn intervals_widen_with_level() {
+        let values = Array1::from_iter((0..=1000).map(|i| i as f64 / 1000.0));
+
+        let ci = interval(values.view(), 0.9, None);
+        assert_close!(ci.0, 0.05);
+        assert_close!(ci.1, 0.95);
+        let ci = interval(values.view(), 0.99, None);
+        assert_close!(ci.0, 0.005);
+        assert_close!(ci.1, 0.995);
+    }
+
+    #[test]
+    fn bca_without_bias_and_skew_is_percentile() {
+        let values = Array1::from_iter((0..=1000).map(|i| i as f64 / 1000.0));
+        let jackknife = array![0.4, 0.5, 0.6];
+
+        let ci = interval(values.view(), 0.95, Some((0.5, jackknife)));
+        assert_close!(ci.0, 0.025);
+        assert_close!(ci.1, 0.975);
+
+        // Most bootstrap estimates below the original one shift the interval up
+        let ci = interval(values.view(), 0.95, Some((0.7, array![0.6, 0.7, 0.8])));
+        assert!(ci.0 > 0.025 && ci.1 > 0.975);
+    }
+
+    #[test]
+    fn log_scale_summaries() {
+        // Rates a factor of ten apart, as the bootstrap of a poorly identified rate gives
+        let rates = array![1e-6, 1e-5, 1e-4];
+        let mut results = Array2::<f64>::ones((3, 7));
+        results.column_mut(0).assign(&rates);
+        results.column_mut(1).assign(&(&rates * 2.0));
+        let analysis = RawAnalysis(results).analyze();
+
+        assert!((analysis.gm_alpha - 1e-5).abs() < 1e-12);
+        assert!(analysis.alpha > 3e-5);
+        assert_close!(analysis.log_sd_alpha, 1.0);
+        assert_close!(analysis.gm_alphabeta, 2.0);
+        assert_close!(analysis.log_sd_alphabeta, 0.0);
+        let log_ci = analysis.log_ci_alpha;
+        assert!(-6.0 < log_ci.0 && log_ci.0 < log_ci.1 && log_ci.1 < -4.0);
+    }
+
+    #[test]
+    fn fixed_parameters_have_no_spread() {
+        let mut results = Array2::<f64>::ones((3, 7));
+        results.column_mut(0).assign(&array![1e-6, 1e-5, 1e-4]);
+        let mut analysis = RawAnalysis(results).analyze();
+        analysis.fixed = Fixed {
+            weight: Some(1.0),
+            ..Fixed::default()
+        };
+        let output = analysis.to_string();
+
+        assert!(output.contains("Weight\t1\n"));
+        assert!(output.contains("SDWeight\tfixed\n"));
+        assert!(output.contains("CIWeight\tfixed\n"));
+        // The estimated parameters keep theirs
+        assert!(!output.contains("SDAlpha\tfixed"));
+        assert!(!output.contains("CIAlpha\tfixed"));
+
+        // Only beta is profiled when alpha is fixed
+        analysis.fixed.alpha = Some(1e-5);
+        analysis.profile_ci_beta = Some(CI(1e-5, 1e-4));
+        let output = analysis.to_string();
+        assert!(output.contains("ProfileCIBeta\t0.00001-0.0001\n"));
+        assert!(!output.contains("ProfileCIAlpha"));
+    }
+}
@@ -2,6 +2,14 @@ use clap::{command, Parser, Subcommand};
 use std::path::PathBuf;
 use std::time::SystemTime;
 
+use crate::{
+    analysis::IntervalMethod,
+    mcmc::Prior,
+    optimizer::Optimizer,
+    pedigree::DivergenceMode,
+    structs::{BootstrapMode, ErrorModel, Estimator, Fixed, MatingSystem, ModelKind},
+};
+
 /// simple tool to separate a methylome by position within a gene
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
@@ -57,6 +65,10 @@ pub struct Windows {
     #[arg(long, default_value_t = 100)]
     pub iterations: usize,
 
+    /// Seed for the random number generator, to make the estimation of every window reproducible. Random if not given
+    #[arg(long)]
+    pub seed: Option<u64>,
+
     #[command(subcommand)]
     pub command: Option<Subcommands>,
 }
@@ -86,6 +98,7 @@ impl Default for Windows {
             force: false,
             cutoff_gene_length: false,
             iterations: 100,
+            seed: None,
         }
     }
 }
@@ -108,9 +121,125 @@ pub struct AlphaBeta {
     /// Minimum posterior probability for a singe basepair read to be included in the estimation
     #[arg(long, short, default_value_t = 0.99)]
     pub posterior_max_filter: f64,
+    /// How the divergence of a pair is measured: from the called status at the sites passing the posterior filter, from the called status at all sites weighted by the posterior of both calls, or from the methylation levels at all sites
+    #[arg(long, value_enum, default_value_t = DivergenceMode::Hard)]
+    pub divergence: DivergenceMode,
     /// Relative or absolute path to an output directory, must exist, EXISTING FILES WILL BE OVERWRITTEN
     #[arg(long, short, default_value_os_t = PathBuf::from("."), value_parser = validate_default_output_dir)]
     pub output: std::path::PathBuf,
+    /// Epimutation model to fit: neutral, with selection against the MM or UU state, or somatic (times in any unit)
+    #[arg(long, value_enum, default_value_t = ModelKind::Neutral)]
+    pub model: ModelKind,
+    /// Mating system used to propagate the lines: selfing, outcrossing to a reference line, or selfing from a heterozygous founder
+    #[arg(long, value_enum, default_value_t = MatingSystem::Selfing)]
+    pub mating: MatingSystem,
+    /// What the fits minimize: least squares of the divergences, unweighted or weighted by the compared sites of every pair, or the negative log-likelihood of the discordant sites of every pair under a binomial or beta-binomial
+    #[arg(long, value_enum, default_value_t = Estimator::LeastSquares)]
+    pub estimator: Estimator,
+    /// How to deal with the divergence expected from miscalled sites: leave it to the intercept, subtract it from the observed divergence, or add it to the prediction of the model
+    #[arg(long, value_enum, default_value_t = ErrorModel::None)]
+    pub error_model: ErrorModel,
+    /// Proportion of unmethylated sites at equilibrium that the penalty of the cost pulls the model towards. The observed proportion at G0 if not given
+    #[arg(long, value_parser = validate_proportion)]
+    pub eqp: Option<f64>,
+    /// Weight of the equilibrium penalty in the cost, zero switches it off
+    #[arg(long, default_value_t = 1.0, value_parser = validate_weight)]
+    pub eqp_weight: f64,
+    /// Choose the weight of the equilibrium penalty by cross-validation over held-out pedigree pairs instead, and write the errors to cv_eqp_weight.txt
+    #[arg(long, default_value_t = false)]
+    pub cv_eqp_weight: bool,
+    /// Number of folds of the cross-validation
+    #[arg(long, default_value_t = 5)]
+    pub cv_folds: usize,
+    /// Hold alpha at this value instead of estimating it
+    #[arg(long, value_parser = validate_rate)]
+    pub fix_alpha: Option<f64>,
+    /// Hold beta at this value instead of estimating it
+    #[arg(long, value_parser = validate_rate)]
+    pub fix_beta: Option<f64>,
+    /// Hold the weight at this value instead of estimating it, e.g. at zero
+    #[arg(long, value_parser = validate_proportion)]
+    pub fix_weight: Option<f64>,
+    /// Hold the intercept at this value instead of estimating it, e.g. at the technical noise known from replicate libraries
+    #[arg(long, value_parser = validate_proportion)]
+    pub fix_intercept: Option<f64>,
+    /// Hold the selection coefficient of the selection models at this value instead of estimating it
+    #[arg(long, value_parser = validate_rate)]
+    pub fix_sel_coef: Option<f64>,
+    /// Seed for the random number generator: The same seed and input give the same results, regardless of the number of threads. Random if not given
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Optimizer used to fit the model: Nelder-Mead, L-BFGS with finite-difference gradients, or a global particle swarm
+    #[arg(long, value_enum, default_value_t = Optimizer::NelderMead)]
+    pub optimizer: Optimizer,
+    /// Convergence tolerance of the optimizer, see the optimizers for its meaning. The defaults of argmin if not given
+    #[arg(long)]
+    pub tolerance: Option<f64>,
+    /// Maximum number of iterations of every single fit. 10000 for the estimation and 1000 for the bootstrap if not given
+    #[arg(long)]
+    pub max_iters: Option<u64>,
+    /// How the bootstrap generates new data: resampling the residuals, simulating the sites down the pedigree under the fitted model, or resampling whole samples or lineages
+    #[arg(long, value_enum, default_value_t = BootstrapMode::Residual)]
+    pub bootstrap: BootstrapMode,
+    /// Refit the model without each methylated sample in turn, to report jackknife standard errors and the influence of every sample
+    #[arg(long, default_value_t = false)]
+    pub jackknife: bool,
+    /// Confidence level of the bootstrap intervals, e.g. 0.9, 0.95 or 0.99
+    #[arg(long, default_value_t = 0.95, value_parser = validate_level)]
+    pub ci_level: f64,
+    /// How the bootstrap intervals are computed: percentile, or bias-corrected and accelerated (BCa), which also runs the jackknife
+    #[arg(long, value_enum, default_value_t = IntervalMethod::Percentile)]
+    pub ci_method: IntervalMethod,
+    /// Also compute profile intervals for alpha and beta, and write the profiles to profile_alpha.txt and profile_beta.txt
+    #[arg(long, default_value_t = false)]
+    pub profile: bool,
+    /// Also sample the posterior of the model with adaptive Metropolis, and write the draws to posterior.npy and their summary to posterior.txt
+    #[arg(long, default_value_t = false)]
+    pub mcmc: bool,
+    /// Number of chains of the sampler
+    #[arg(long, default_value_t = 4)]
+    pub chains: usize,
+    /// Number of draws of every chain, after as many warmup iterations
+    #[arg(long, default_value_t = 2000)]
+    pub draws: usize,
+    /// Prior on the log10 of alpha for the sampler: uniform:<low>:<high> or normal:<mean>:<sd>
+    #[arg(long, default_value_t = Prior::default(), value_parser = Prior::parse)]
+    pub prior_alpha: Prior,
+    /// Prior on the log10 of beta for the sampler: uniform:<low>:<high> or normal:<mean>:<sd>
+    #[arg(long, default_value_t = Prior::default(), value_parser = Prior::parse)]
+    pub prior_beta: Prior,
+}
+
+fn validate_level(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(level) if level > 0.0 && level < 1.0 => Ok(level),
+        _ => Err(format!(
+            "The confidence level must be between 0 and 1, not {s}"
+        )),
+    }
+}
+
+fn validate_proportion(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+        _ => Err(format!("The proportion must be between 0 and 1, not {s}")),
+    }
+}
+
+fn validate_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(r) if r > 0.0 && r < 1.0 => Ok(r),
+        _ => Err(format!(
+            "The rate must be between 0 and 1, exclusive, not {s}"
+        )),
+    }
+}
+
+fn validate_weight(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(w) if w >= 0.0 => Ok(w),
+        _ => Err(format!("The weight must not be negative, not {s}")),
+    }
 }
 
 fn validate_default_output_dir(s: &str) -> Result<PathBuf, String> {
@@ -146,7 +275,46 @@ impl AlphaBeta {
             nodes: output_dir.join("nodelist.txt"),
             output: output_dir,
             posterior_max_filter: 0.99,
+            divergence: DivergenceMode::Hard,
             iterations,
+            model: ModelKind::Neutral,
+            mating: MatingSystem::Selfing,
+            estimator: Estimator::LeastSquares,
+            error_model: ErrorModel::None,
+            eqp: None,
+            eqp_weight: 1.0,
+            cv_eqp_weight: false,
+            cv_folds: 5,
+            fix_alpha: None,
+            fix_beta: None,
+            fix_weight: None,
+            fix_intercept: None,
+            fix_sel_coef: None,
+            seed: None,
+            optimizer: Optimizer::NelderMead,
+            tolerance: None,
+            max_iters: None,
+            bootstrap: BootstrapMode::Residual,
+            jackknife: false,
+            ci_level: 0.95,
+            ci_method: IntervalMethod::Percentile,
+            profile: false,
+            mcmc: false,
+            chains: 4,
+            draws: 2000,
+            prior_alpha: Prior::default(),
+            prior_beta: Prior::default(),
+        }
+    }
+
+    /// The parameters to hold at a given value instead of estimating them
+    pub fn fixed(&self) -> Fixed {
+        Fixed {
+            alpha: self.fix_alpha,
+            beta: self.fix_beta,
+            weight: self.fix_weight,
+            intercept: self.fix_intercept,
+            sel_coef: self.fix_sel_coef,
         }
     }
 }
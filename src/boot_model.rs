@@ -1,106 +1,152 @@
 use indicatif::ProgressBar;
-use rand::{distributions::Slice, thread_rng, Rng};
+use rand::{distributions::Slice, Rng};
 use rayon::prelude::*;
-use std::{path::Path, sync::Mutex};
+use std::{collections::HashMap, path::Path};
 
-use argmin::{core::Executor, solver::neldermead::NelderMead};
-use ndarray::{array, s, Array1, Array2, Axis};
+use ndarray::{s, Array1, Array2, Axis};
 
 use crate::{
-    analysis::{Analysis, RawAnalysis},
-    pedigree::Pedigree,
-    structs::{Model, PredictedDivergence, Problem, Progress, Residuals},
+    analysis::{Analysis, IntervalMethod, RawAnalysis},
+    divergence::mat3,
+    jackknife::Jackknife,
+    optimizer::OptimizerSettings,
+    pedigree::{Genealogy, Pedigree},
+    structs::{
+        iteration_rng, BootstrapMode, Estimator, Fixed, MatingSystem, Model, ModelKind,
+        PredictedDivergence, Problem, Progress, Residuals,
+    },
     *,
 };
+/// Offset of the random number streams of the bootstrap, see [`iteration_rng`]
+const BOOTSTRAP_STREAM: u64 = 1 << 32;
+/// How often the block bootstrap redraws blocks that form no pair before it gives up
+const MAX_REDRAWS: usize = 1000;
+
+/// Bootstrap the model fitted to `pedigree` to estimate the uncertainty of its parameters.
+///
+/// The parametric and block bootstraps need the `genealogy` of the samples, the residual bootstrap ignores it.
+/// The confidence intervals have the given `level`, BCa intervals need the `jackknife` of the model.
 // The number of arguments is not ideal but it is just a complex function
 #[allow(clippy::too_many_arguments)]
 pub fn run(
     pedigree: &Pedigree,
     params: &Model,
+    kind: ModelKind,
+    mating: MatingSystem,
+    estimator: Estimator,
+    fixed: Fixed,
     pred_div: PredictedDivergence,
     residuals: Residuals,
+    mode: BootstrapMode,
+    genealogy: Option<&Genealogy>,
+    level: f64,
+    method: IntervalMethod,
+    jackknife: Option<&Jackknife>,
     p0uu: f64,
     eqp: f64,
     eqp_weight: f64,
     n_boot: usize,
+    seed: u64,
+    optimizer: OptimizerSettings,
     pb: Option<&ProgressBar>,
     output_dir: &Path,
 ) -> Result<(Analysis, RawAnalysis), Box<dyn std::error::Error>> {
     let alternative_pb = Progress::new("BootModel", n_boot).0;
     let pb = pb.unwrap_or(&alternative_pb);
 
-    let p0mm = 1.0 - p0uu;
-    let p0um = 0.0;
-
-    assert_eq!(p0mm + p0uu + p0um, 1.0);
+    // Alpha, Beta, Weight, Intercept, pr_mm, pr_um, pr_uu (, sel_coef)
+    let columns = if kind.has_selection() { 8 } else { 7 };
+    // Separate from the streams of the model estimation
+    let stream = BOOTSTRAP_STREAM + kind as u64;
 
-    // Alpha, Beta, Weight, Intercept, pr_mm, pr_um, pr_uu
-    let results = Mutex::new(Array2::<f64>::zeros((0, 7)));
+    let fitted = Problem {
+        fixed,
+        ..Problem::new(
+            pedigree.clone(),
+            p0uu,
+            eqp,
+            eqp_weight,
+            kind,
+            mating,
+            estimator,
+        )
+    };
+    let genealogy = match (mode, genealogy) {
+        (BootstrapMode::Residual, _) => None,
+        (_, None) => {
+            return Err(Error::Simple(
+                "The parametric and block bootstraps need the genealogy of the samples",
+            )
+            .into())
+        }
+        (_, Some(genealogy)) if genealogy.pairs.len() != pedigree.nrows() => {
+            return Err(Error::Simple("The genealogy does not belong to the pedigree").into())
+        }
+        (_, genealogy) => genealogy,
+    };
 
     // Optimization loop
-    (0..n_boot).into_par_iter().for_each(|_i| {
-        // pedigree[,"div.obs"]<-pedigree[,"div.pred"]+sample(pedigree[,"residual"], nrow(pedigree), replace=TRUE)
-        let rng = thread_rng();
-        let residual_dist = Slice::new(&residuals).unwrap();
-        let residual_sample: Vec<&f64> = rng
-            .sample_iter(&residual_dist)
-            .take(pedigree.len_of(Axis(0)))
-            .collect();
-        assert!(pred_div.len() == residual_sample.len());
-        let div_ops: Vec<f64> = pred_div
-            .iter()
-            .zip(residual_sample.iter())
-            .map(|(a, b)| a + *b)
-            .collect();
+    let results: Vec<Vec<f64>> = (0..n_boot)
+        .into_par_iter()
+        .map(|i| {
+            let mut rng = iteration_rng(seed, stream, i);
+            let pedigree = match (mode, genealogy) {
+                (BootstrapMode::Parametric, Some(genealogy)) => {
+                    let div_ops = simulate(&fitted, params, genealogy, &mut rng)?;
+                    let mut pedigree = pedigree.clone();
+                    pedigree.slice_mut(s![.., 3]).assign(&Array1::from(div_ops));
+                    pedigree
+                }
+                (BootstrapMode::Samples | BootstrapMode::Lineages, Some(genealogy)) => {
+                    resample_blocks(pedigree, genealogy, mode, &mut rng)?
+                }
+                _ => {
+                    // pedigree[,"div.obs"]<-pedigree[,"div.pred"]+sample(pedigree[,"residual"], nrow(pedigree), replace=TRUE)
+                    let residual_dist = Slice::new(&residuals).unwrap();
+                    let residual_sample: Vec<&f64> = (&mut rng)
+                        .sample_iter(&residual_dist)
+                        .take(pedigree.len_of(Axis(0)))
+                        .collect();
+                    assert!(pred_div.len() == residual_sample.len());
+                    let div_ops: Vec<f64> = pred_div
+                        .iter()
+                        .zip(residual_sample.iter())
+                        .map(|(a, b)| a + *b)
+                        .collect();
 
-        let mut pedigree = pedigree.clone();
-        pedigree.slice_mut(s![.., 3]).assign(&Array1::from(div_ops));
+                    let mut pedigree = pedigree.clone();
+                    pedigree.slice_mut(s![.., 3]).assign(&Array1::from(div_ops));
+                    pedigree
+                }
+            };
 
-        let problem = Problem {
-            pedigree,
-            eqp_weight,
-            eqp,
-            p_mm: p0mm,
-            p_um: p0um,
-            p_uu: p0uu,
-        };
-        // Run Nelder-Mead optimization
-        // Use the previous result as the initial guess, supplement with random values close-by
-        let nm = NelderMead::new(vec![
-            params.to_vec(),
-            params.vary().to_vec(),
-            params.vary().to_vec(),
-            params.vary().to_vec(),
-            params.vary().to_vec(),
-        ]);
-
-        let res = Executor::new(problem, nm)
-            .configure(|state| {
-                state
-                    // .param(vec![alpha, beta, weight, intercept])
-                    .max_iters(1000)
-            })
-            .run()
-            .expect("Failed to run Nelder-Mead optimization");
-
-        let m = Model::from_vec(&res.state.best_param.unwrap());
-
-        let pr_mm = m.est_mm();
-        let pr_um = m.est_um();
-        let pr_uu = m.est_uu();
-        let r = array![m.alpha, m.beta, m.weight, m.intercept, pr_mm, pr_um, pr_uu];
-        results
-            .lock()
-            .unwrap()
-            .push(Axis(0), r.view())
-            .expect("Insertion failed");
-        // let c = counter.fetch_add(1, Ordering::Relaxed);
-        pb.inc(1);
-        //  println!("Progress: {}%", ((c * 100) as f32 / (n_boot) as f32));
-    });
+            let problem = Problem {
+                pedigree,
+                ..fitted.clone()
+            };
+            // Use the previous result as the initial guess, supplement with random values close-by
+            let mut simplex = vec![problem.to_params(params)];
+            simplex.extend(
+                (0..problem.n_params()).map(|_| problem.to_params(&problem.vary(params, &mut rng))),
+            );
+
+            let minimum = optimizer
+                .minimize(&problem, simplex, 1000)
+                .expect("Failed to run optimization");
+
+            let m = problem.to_model(&minimum.param);
+
+            let r = problem.estimates(&m);
+            // let c = counter.fetch_add(1, Ordering::Relaxed);
+            pb.inc(1);
+            //  println!("Progress: {}%", ((c * 100) as f32 / (n_boot) as f32));
+            Ok(r)
+        })
+        .collect::<Result<_, Error>>()?;
     pb.finish();
 
-    let results = results.into_inner().unwrap();
+    // Rows stay in the order of the iterations, independent of the threads
+    let results = Array2::from_shape_vec((n_boot, columns), results.concat())?;
 
     plot::bootstrap(
         results.column(0).to_vec(),
@@ -109,7 +155,243 @@ pub fn run(
     )?;
 
     let raw_analysis = RawAnalysis(results);
-    let analysis = raw_analysis.analyze();
+    let mut analysis = match (method, jackknife) {
+        (IntervalMethod::Percentile, _) => raw_analysis.analyze_with(level, None),
+        (IntervalMethod::BCa, Some(jackknife)) => {
+            let estimates: Vec<f64> = jackknife
+                .samples
+                .iter()
+                .flat_map(|s| fitted.estimates(&s.model))
+                .collect();
+            let estimates = Array2::from_shape_vec((jackknife.samples.len(), columns), estimates)?;
+            raw_analysis.analyze_with(level, Some((&fitted.estimates(params), &estimates)))
+        }
+        (IntervalMethod::BCa, None) => {
+            return Err(Error::Simple("BCa intervals need a jackknife for the acceleration").into())
+        }
+    };
+    analysis.mating = mating;
+    analysis.bootstrap = mode;
+    analysis.estimator = estimator;
+    analysis.fixed = fixed;
 
     Ok((analysis, raw_analysis))
 }
+
+/// Draw samples, or whole lineages, with replacement and build the pedigree of all pairs of the drawn samples.
+///
+/// The rows keep their observed divergence. A sample drawn twice appears in two rows with every other sample, but never paired with itself.
+/// Draws without any pair are redrawn, up to [`MAX_REDRAWS`] times.
+fn resample_blocks(
+    pedigree: &Pedigree,
+    genealogy: &Genealogy,
+    mode: BootstrapMode,
+    rng: &mut impl Rng,
+) -> Result<Pedigree, Error> {
+    let blocks = match mode {
+        BootstrapMode::Lineages => genealogy.lineages(),
+        _ => genealogy.samples().into_iter().map(|s| vec![s]).collect(),
+    };
+    let rows: HashMap<(usize, usize), usize> = genealogy
+        .pairs
+        .iter()
+        .enumerate()
+        .flat_map(|(row, (a, b))| [((*a, *b), row), ((*b, *a), row)])
+        .collect();
+
+    // A single sample, or samples without a common ancestor, never form a pair
+    let block_of: HashMap<usize, usize> = blocks
+        .iter()
+        .enumerate()
+        .flat_map(|(i, block)| block.iter().map(move |sample| (*sample, i)))
+        .collect();
+    let pairable = genealogy.pairs.iter().any(|(a, b)| {
+        match (block_of.get(a), block_of.get(b)) {
+            // Different blocks have to be drawn together, a single block has its pairs within
+            (Some(i), Some(j)) => i == j || blocks.len() > 1,
+            _ => false,
+        }
+    });
+    if !pairable {
+        return Err(Error::Simple(
+            "No resampled blocks form a pair of the pedigree, the block bootstrap needs pairs within or between blocks",
+        ));
+    }
+
+    for _ in 0..MAX_REDRAWS {
+        let drawn: Vec<usize> = (0..blocks.len())
+            .flat_map(|_| blocks[rng.gen_range(0..blocks.len())].iter().copied())
+            .collect();
+
+        let mut selected = Vec::new();
+        for (i, a) in drawn.iter().enumerate() {
+            for b in &drawn[i + 1..] {
+                // Pairs without a common ancestor are not in the pedigree
+                if let Some(row) = rows.get(&(*a, *b)) {
+                    selected.push(*row);
+                }
+            }
+        }
+        // Drawing the same sample over and over leaves nothing to fit
+        if !selected.is_empty() {
+            return Ok(Pedigree::from(pedigree.select(Axis(0), &selected)));
+        }
+    }
+    Err(Error::Simple(
+        "The resampled blocks did not form a single pair of the pedigree in too many draws",
+    ))
+}
+
+/// Simulate the divergence of every pair of the pedigree under `model`.
+///
+/// The states of the sites are drawn at the founders and passed down the genealogy, each node drawing its state from the transition probabilities of its parent's.
+/// The divergence of a pair is the share of differing alleles of its two samples over as many sites as the pair compared, plus the intercept of the model.
+pub fn simulate(
+    problem: &Problem,
+    model: &Model,
+    genealogy: &Genealogy,
+    rng: &mut impl Rng,
+) -> Result<Vec<f64>, Error> {
+    let n = genealogy.generations.len();
+    // Parents before their children
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|a, b| genealogy.generations[*a].total_cmp(&genealogy.generations[*b]));
+
+    // Founders are drawn from the state probabilities at their generation, the other nodes from the transition of their parent's state
+    let sv_gzero = problem.sv_gzero(model);
+    let transitions = (0..n)
+        .map(|node| match genealogy.parents[node] {
+            Some(parent) => {
+                let t = genealogy.generations[node] - genealogy.generations[parent];
+                Ok(mat3(&problem.transition(model, t)?))
+            }
+            None => {
+                let founder =
+                    sv_gzero.dot(&problem.transition(model, genealogy.generations[node])?);
+                Ok([[founder[0], founder[1], founder[2]]; 3])
+            }
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    // States are UU, UM and MM, so their difference counts the differing alleles
+    let draw = |p: &[f64; 3], u: f64| {
+        if u < p[0] {
+            0
+        } else if u < p[0] + p[1] {
+            1
+        } else {
+            2
+        }
+    };
+    // Every pair compares as many sites as it did in the methylomes, or the average if the pedigree doesn't say
+    let sites: Vec<usize> = match problem.pedigree.sites() {
+        Some(sites) => sites.iter().map(|s| (s.round() as usize).max(1)).collect(),
+        None => vec![genealogy.sites.max(1); genealogy.pairs.len()],
+    };
+    let mut states = vec![0_usize; n];
+    let mut differences = vec![0_usize; genealogy.pairs.len()];
+    for site in 0..sites.iter().copied().max().unwrap_or(0) {
+        for &node in &order {
+            let from = genealogy.parents[node].map_or(0, |parent| states[parent]);
+            states[node] = draw(&transitions[node][from], rng.gen());
+        }
+        for ((d, (a, b)), n_sites) in differences.iter_mut().zip(&genealogy.pairs).zip(&sites) {
+            if site < *n_sites {
+                *d += states[*a].abs_diff(states[*b]);
+            }
+        }
+    }
+
+    // Miscalls come on top of the simulated states, if the model accounts for them
+    let misclassification = problem.pedigree.misclassification();
+    Ok(differences
+        .into_iter()
+        .zip(sites)
+        .enumerate()
+        .map(|(i, (d, n_sites))| {
+            let miscalls = misclassification.map_or(0.0, |e| e[i]);
+            model.intercept + d as f64 / (2.0 * n_sites as f64) + miscalls
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn resampled_blocks_keep_observed_pairs() {
+        let (pedigree, _, genealogy) = Pedigree::example();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for mode in [BootstrapMode::Samples, BootstrapMode::Lineages] {
+            for _ in 0..20 {
+                let resampled = resample_blocks(&pedigree, &genealogy, mode, &mut rng).unwrap();
+                assert!(resampled.nrows() > 0);
+                for row in resampled.rows() {
+                    assert!(pedigree.rows().into_iter().any(|r| r == row));
+                }
+            }
+        }
+
+        // A single sample has no one to pair with
+        let mut single = genealogy.clone();
+        let first = single.samples()[0];
+        for (node, unmethylated) in single.unmethylated.iter_mut().enumerate() {
+            if node != first {
+                *unmethylated = None;
+            }
+        }
+        assert!(resample_blocks(&pedigree, &single, BootstrapMode::Samples, &mut rng).is_err());
+    }
+
+    #[test]
+    fn simulation_matches_predicted_divergence() {
+        let (pedigree, p0uu, mut genealogy) = Pedigree::example();
+        assert_eq!(genealogy.pairs.len(), pedigree.nrows());
+
+        let model = Model {
+            alpha: 0.02,
+            beta: 0.05,
+            weight: 0.5,
+            intercept: 0.01,
+            sel_coef: None,
+            on_boundary: false,
+        };
+        let problem = Problem::new(
+            pedigree,
+            p0uu,
+            p0uu,
+            1.0,
+            ModelKind::Neutral,
+            MatingSystem::Selfing,
+            Estimator::LeastSquares,
+        );
+        // Without per-pair sites, every pair compares the average number of sites
+        let mut averaged = problem.clone();
+        averaged.pedigree = Pedigree::from(problem.pedigree.slice(s![.., ..4]).to_owned());
+        genealogy.sites = 100_000;
+        let simulated = simulate(&averaged, &model, &genealogy, &mut StdRng::seed_from_u64(0))
+            .expect("Simulation failed");
+        let predicted = problem.divergence(&model).unwrap().dt1t2;
+
+        for (s, p) in simulated.iter().zip(&predicted) {
+            assert!((s - model.intercept - p).abs() < 0.005);
+        }
+
+        // A pair that compared a single site has two alleles to differ in
+        let mut problem = problem;
+        problem.pedigree.column_mut(4).fill(100_000.0);
+        problem.pedigree[[0, 4]] = 1.0;
+        let simulated = simulate(&problem, &model, &genealogy, &mut StdRng::seed_from_u64(0))
+            .expect("Simulation failed");
+        assert!([0.0, 0.5, 1.0]
+            .iter()
+            .any(|d| (simulated[0] - model.intercept - d).abs() < 1e-12));
+        for (s, p) in simulated.iter().zip(&predicted).skip(1) {
+            assert!((s - model.intercept - p).abs() < 0.005);
+        }
+    }
+}
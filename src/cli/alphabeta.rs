@@ -1,5 +1,4 @@
 use alphabeta::alphabeta::run;
-use alphabeta::alphabeta::steady_state;
 use alphabeta::{arguments::AlphaBeta as Args, progress};
 
 use clap::Parser;
@@ -14,15 +13,23 @@ fn main() {
 
     match result {
         Err(e) => println!("Error: {e}"),
-        Ok((model, analysis, raw_analysis, pedigree, obs_steady_state)) => {
+        Ok((
+            model,
+            analysis,
+            raw_analysis,
+            pedigree,
+            est_steady_state,
+            obs_steady_state,
+            comparison,
+            diagnostics,
+            jackknife,
+        )) => {
             println!("##########");
             println!("Results:\n");
             println!("{model}");
             println!("{analysis}");
-            println!(
-                "Estimated steady state {}",
-                steady_state(model.alpha, model.beta)
-            );
+            println!("{comparison}");
+            println!("Estimated steady state {est_steady_state}");
             println!("Observed steady state methylation {obs_steady_state}");
             println!("##########");
             pedigree
@@ -31,6 +38,18 @@ fn main() {
             analysis
                 .to_file(&args.output.join("analysis.txt"))
                 .expect("Failed to write results");
+            comparison
+                .to_file(&args.output.join("comparison.txt"))
+                .expect("Failed to write model comparison");
+            diagnostics
+                .to_file(&args.output.join("diagnostics.txt"))
+                .expect("Failed to write multistart diagnostics");
+            if let Some(jackknife) = jackknife {
+                println!("{jackknife}");
+                jackknife
+                    .to_file(&args.output.join("jackknife.txt"))
+                    .expect("Failed to write jackknife");
+            }
             write_npy(args.output.join("raw.npy"), &raw_analysis.0)
                 .expect("Could not save raw results to file.");
         }
@@ -1,5 +1,4 @@
 use alphabeta::{
-    alphabeta::steady_state,
     analysis::Analysis,
     arguments::{Subcommands, Windows as Args},
     extract::extract,
@@ -31,6 +30,7 @@ fn main() {
 }
 
 fn alphabeta_multiple(args: Args, max_gene_length: u32, distribution: Vec<i32>) {
+    let seed = args.seed;
     let regions = vec![
         (Region::Upstream, args.cutoff),
         (Region::Gene, max_gene_length),
@@ -53,26 +53,37 @@ fn alphabeta_multiple(args: Args, max_gene_length: u32, distribution: Vec<i32>)
         for window in (0..max).step_by(args.window_step as usize) {
             pb.inc(1);
 
-            let args = alphabeta::arguments::AlphaBeta::default(
+            let mut args = alphabeta::arguments::AlphaBeta::default(
                 args.output_dir
                     .join(region.0.to_string())
                     .join(window.to_string()),
                 args.iterations,
             );
+            args.seed = seed;
 
             let alphabeta_result = alphabeta::alphabeta::run(args, &multi);
             match alphabeta_result {
                 Err(e) => println!("Error: {e}"),
-                Ok((model, analysis, raw_analysis, _, obs_meth_lvl)) => {
-                    results.push((model, analysis, region.0.clone(), obs_meth_lvl));
+                Ok((model, analysis, raw_analysis, _, est_meth_lvl, obs_meth_lvl, _, _, _)) => {
+                    results.push((
+                        model,
+                        analysis,
+                        region.0.clone(),
+                        est_meth_lvl,
+                        obs_meth_lvl,
+                    ));
                     raw_analyses.push(Axis(2), raw_analysis.0.view()).unwrap();
                 }
             }
         }
     }
     pb.finish();
-    let mut print = String::from("run;window;cg_count;region;alpha;beta;1/2*(alpha+beta);pred_steady_state;obs_steady_state;sd_alpha;sd_beta;ci_alpha_0.025;ci_alpha_0.975;ci_beta_0.025;ci_beta_0.975\n");
-    for (i, ((model, analysis, region, obs_meth_lvl), d)) in
+    // Name the interval columns by their quantiles, e.g. 0.025 and 0.975 for 95% intervals
+    let level = results.first().map_or(0.95, |r| r.1.level);
+    let quantile = |q: f64| (q * 1e6).round() / 1e6;
+    let (lower, upper) = (quantile((1.0 - level) / 2.0), quantile((1.0 + level) / 2.0));
+    let mut print = format!("run;window;cg_count;region;alpha;beta;1/2*(alpha+beta);pred_steady_state;obs_steady_state;sd_alpha;sd_beta;ci_alpha_{lower};ci_alpha_{upper};ci_beta_{lower};ci_beta_{upper}\n");
+    for (i, ((model, analysis, region, est_meth_lvl, obs_meth_lvl), d)) in
         results.iter().zip(distribution.iter()).enumerate()
     {
         print += &format!(
@@ -84,7 +95,7 @@ fn alphabeta_multiple(args: Args, max_gene_length: u32, distribution: Vec<i32>)
             model.alpha,
             model.beta,
             0.5 * (model.alpha + model.beta),
-            steady_state(model.alpha, model.beta),
+            est_meth_lvl,
             obs_meth_lvl,
             analysis.sd_alpha,
             analysis.sd_beta,
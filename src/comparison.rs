@@ -0,0 +1,240 @@
+use std::{fmt::Display, fs::File, io::Write, path::Path};
+
+use statrs::distribution::{ChiSquared, ContinuousCDF, FisherSnedecor};
+
+use crate::{
+    structs::{Model, Problem, Residuals},
+    *,
+};
+
+/// Goodness of fit of a single model, based on its residual sum of squares, or on its log-likelihood if it was fitted by maximum likelihood
+#[derive(Debug, Clone)]
+pub struct Fit {
+    pub name: &'static str,
+    /// Number of estimated parameters
+    pub n_params: usize,
+    /// Number of pedigree pairs
+    pub n: usize,
+    pub rss: f64,
+    /// Maximized log-likelihood, `None` for least squares fits
+    pub log_likelihood: Option<f64>,
+    pub aic: f64,
+    pub bic: f64,
+}
+
+/// Test of a reduced model against a full model it is nested in:
+/// A likelihood ratio test if both were fitted by maximum likelihood, an F-test of their residual sums of squares otherwise
+#[derive(Debug, Clone)]
+pub struct FTest {
+    pub reduced: &'static str,
+    pub full: &'static str,
+    /// Whether the test is a likelihood ratio test
+    pub likelihood_ratio: bool,
+    /// F, or the chi-squared statistic of the likelihood ratio test
+    pub f: f64,
+    pub p_value: f64,
+}
+
+/// Comparison of ABnull, ABneutral and, if fitted, a selection model.
+///
+/// The tests check whether the additional parameters of the full model improve the fit more than expected by chance.
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    pub fits: Vec<Fit>,
+    pub tests: Vec<FTest>,
+}
+
+impl Fit {
+    pub fn new(name: &'static str, n_params: usize, residuals: &Residuals) -> Self {
+        let n = residuals.len();
+        let rss = residuals.iter().map(|r| r.powi(2)).sum::<f64>();
+        let log_likelihood_term = n as f64 * (rss / n as f64).ln();
+        Fit {
+            name,
+            n_params,
+            n,
+            rss,
+            log_likelihood: None,
+            aic: log_likelihood_term + 2.0 * n_params as f64,
+            bic: log_likelihood_term + n_params as f64 * (n as f64).ln(),
+        }
+    }
+
+    /// Goodness of a fit by maximum likelihood, with the `residuals` only for the record
+    pub fn from_log_likelihood(
+        name: &'static str,
+        n_params: usize,
+        residuals: &Residuals,
+        log_likelihood: f64,
+    ) -> Self {
+        let n = residuals.len();
+        Fit {
+            name,
+            n_params,
+            n,
+            rss: residuals.iter().map(|r| r.powi(2)).sum::<f64>(),
+            log_likelihood: Some(log_likelihood),
+            aic: -2.0 * log_likelihood + 2.0 * n_params as f64,
+            bic: -2.0 * log_likelihood + n_params as f64 * (n as f64).ln(),
+        }
+    }
+
+    /// Goodness of fit of a `model` with the `predicted` divergence (without intercept) to the pedigree of `problem`,
+    /// by the criterion its estimator minimized.
+    ///
+    /// The likelihood estimators are judged by their log-likelihood, and the overdispersion of the beta-binomial counts as an estimated parameter.
+    pub fn of(
+        name: &'static str,
+        n_params: usize,
+        problem: &Problem,
+        model: &Model,
+        predicted: &[f64],
+        residuals: &Residuals,
+    ) -> Result<Self, Error> {
+        if !problem.estimator.is_likelihood() {
+            return Ok(Fit::new(name, n_params, residuals));
+        }
+        let log_likelihood = problem.log_likelihood(problem.lack_of_fit(model, predicted)?);
+        Ok(Fit::from_log_likelihood(
+            name,
+            n_params + problem.estimator.n_params(),
+            residuals,
+            log_likelihood,
+        ))
+    }
+}
+
+impl FTest {
+    pub fn new(reduced: &Fit, full: &Fit) -> Self {
+        let df_extra = full.n_params.saturating_sub(reduced.n_params) as f64;
+        let df_residual = full.n.saturating_sub(full.n_params) as f64;
+
+        if let (Some(reduced_ll), Some(full_ll)) = (reduced.log_likelihood, full.log_likelihood) {
+            let chi = if df_extra > 0.0 {
+                2.0 * (full_ll - reduced_ll)
+            } else {
+                f64::NAN
+            };
+            let p_value = match ChiSquared::new(df_extra) {
+                Ok(distribution) if chi.is_finite() => 1.0 - distribution.cdf(chi.max(0.0)),
+                _ => f64::NAN,
+            };
+            return FTest {
+                reduced: reduced.name,
+                full: full.name,
+                likelihood_ratio: true,
+                f: chi,
+                p_value,
+            };
+        }
+
+        // Models with the same number of estimated parameters, e.g. with a fixed selection coefficient, are not nested
+        let f = if df_extra > 0.0 {
+            ((reduced.rss - full.rss) / df_extra) / (full.rss / df_residual)
+        } else {
+            f64::NAN
+        };
+
+        // Not enough pairs to test, or a perfect fit
+        let p_value = match FisherSnedecor::new(df_extra, df_residual) {
+            Ok(distribution) if f.is_finite() => 1.0 - distribution.cdf(f.max(0.0)),
+            _ => f64::NAN,
+        };
+
+        FTest {
+            reduced: reduced.name,
+            full: full.name,
+            likelihood_ratio: false,
+            f,
+            p_value,
+        }
+    }
+}
+
+impl Comparison {
+    pub fn new(null: Fit, neutral: Fit, selection: Option<Fit>) -> Self {
+        let mut tests = vec![FTest::new(&null, &neutral)];
+        if let Some(selection) = &selection {
+            tests.push(FTest::new(&neutral, selection));
+        }
+
+        let mut fits = vec![null, neutral];
+        fits.extend(selection);
+
+        Comparison { fits, tests }
+    }
+
+    pub fn to_file(&self, path: &Path) -> std::io::Result<()> {
+        println!("Writing model comparison to file: {}", path.display());
+        let mut file = File::create(path)?;
+        file.write_all(self.to_string().as_bytes())
+    }
+}
+
+impl Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let likelihood = self.fits.iter().all(|fit| fit.log_likelihood.is_some());
+        if likelihood {
+            writeln!(f, "Model\tParameters\tRSS\tLogLikelihood\tAIC\tBIC")?;
+        } else {
+            writeln!(f, "Model\tParameters\tRSS\tAIC\tBIC")?;
+        }
+        for fit in &self.fits {
+            write!(f, "{}\t{}\t{}\t", fit.name, fit.n_params, fit.rss)?;
+            if let Some(log_likelihood) = fit.log_likelihood {
+                write!(f, "{log_likelihood}\t")?;
+            }
+            writeln!(f, "{}\t{}", fit.aic, fit.bic)?;
+        }
+        // The fits by maximum likelihood are tested by their likelihood ratio, not by their residual sum of squares
+        let statistic = if likelihood { "ChiSquared" } else { "F" };
+        writeln!(f, "\nReduced\tFull\t{statistic}\tPValue")?;
+        for test in &self.tests {
+            writeln!(
+                f,
+                "{}\t{}\t{}\t{}",
+                test.reduced, test.full, test.f, test.p_value
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn better_fit_is_significant() {
+        let null = Fit::new(
+            "ABnull",
+            1,
+            &vec![1.0, -1.0, 1.0, -1.0, 1.0, -1.0, 1.0, -1.0],
+        );
+        let neutral = Fit::new(
+            "ABneutral",
+            4,
+            &vec![0.01, -0.01, 0.01, -0.01, 0.01, -0.01, 0.01, -0.01],
+        );
+        let comparison = Comparison::new(null, neutral, None);
+
+        assert!(comparison.fits[1].aic < comparison.fits[0].aic);
+        assert!(comparison.tests[0].p_value < 0.001);
+    }
+
+    #[test]
+    fn likelihood_fits_are_compared_by_likelihood_ratio() {
+        let residuals = vec![0.01, -0.01, 0.01, -0.01];
+        let null = Fit::from_log_likelihood("ABnull", 1, &residuals, -120.0);
+        let neutral = Fit::from_log_likelihood("ABneutral", 5, &residuals, -100.0);
+        let comparison = Comparison::new(null, neutral, None);
+
+        // Equal residuals, but a much higher likelihood
+        assert_eq!(comparison.fits[1].aic, 210.0);
+        assert!(comparison.fits[1].aic < comparison.fits[0].aic);
+        assert!(comparison.tests[0].likelihood_ratio);
+        assert_eq!(comparison.tests[0].f, 40.0);
+        assert!(comparison.tests[0].p_value < 0.001);
+        assert!(comparison.to_string().contains("ChiSquared"));
+    }
+}
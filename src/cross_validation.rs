@@ -0,0 +1,149 @@
+use std::{fmt::Display, fs::File, io::Write, path::Path};
+
+use indicatif::ProgressBar;
+use ndarray::Axis;
+use rand::seq::SliceRandom;
+
+use crate::{
+    ab_neutral::estimate,
+    optimizer::OptimizerSettings,
+    pedigree::Pedigree,
+    structs::{iteration_rng, Problem, Progress},
+    *,
+};
+
+/// Offset of the random number stream that assigns the pairs to the folds, see [`iteration_rng`]
+const CV_STREAM: u64 = 4 << 32;
+
+/// Weights of the equilibrium penalty that the cross-validation chooses from
+pub const EQP_WEIGHTS: [f64; 6] = [0.0, 0.01, 0.1, 1.0, 10.0, 100.0];
+
+/// Cross-validation of the weight of the equilibrium penalty
+#[derive(Debug, Clone)]
+pub struct CrossValidation {
+    pub folds: usize,
+    pub weights: Vec<f64>,
+    /// Lack of fit of the held-out pairs, summed over the folds, for every weight
+    pub errors: Vec<f64>,
+    /// Weight with the lowest error
+    pub best: f64,
+}
+
+/// Choose the weight of the equilibrium penalty of `problem` from `weights` by `folds`-fold cross-validation over the pedigree pairs.
+///
+/// For every weight, the model is fitted without the pairs of one fold and judged by how well it predicts them,
+/// in the lack of fit of the problem's estimator. All weights see the same folds and starting points.
+pub fn eqp_weight(
+    problem: &Problem,
+    weights: &[f64],
+    folds: usize,
+    n_starts: usize,
+    seed: u64,
+    optimizer: OptimizerSettings,
+) -> Result<CrossValidation, Box<dyn std::error::Error>> {
+    let n = problem.pedigree.nrows();
+    if folds < 2 || folds > n {
+        return Err(Error::Simple(
+            "The number of folds must be between two and the number of pairs",
+        )
+        .into());
+    }
+
+    let mut rows: Vec<usize> = (0..n).collect();
+    rows.shuffle(&mut iteration_rng(seed, CV_STREAM, 0));
+    let subset = |rows: Vec<usize>| Pedigree::from(problem.pedigree.select(Axis(0), &rows));
+
+    let pb = Progress::new("CrossValidation", weights.len() * folds);
+    // The fits of the folds would each show their own progress
+    let hidden = ProgressBar::hidden();
+    let mut errors = Vec::with_capacity(weights.len());
+    for &weight in weights {
+        let mut error = 0.0;
+        for fold in 0..folds {
+            // Pairs in the fold are held out, the others train the model
+            let rows_in = |in_fold: bool| {
+                let rows = rows.iter().enumerate();
+                let rows = rows.filter(|(i, _)| (i % folds == fold) == in_fold);
+                rows.map(|(_, row)| *row).collect()
+            };
+
+            let training = Problem {
+                pedigree: subset(rows_in(false)),
+                eqp_weight: weight,
+                ..problem.clone()
+            };
+            let (model, _, _, _) = estimate(&training, n_starts, seed, optimizer, Some(&hidden))?;
+
+            let held_out = Problem {
+                pedigree: subset(rows_in(true)),
+                ..problem.clone()
+            };
+            error += held_out.lack_of_fit(&model, &held_out.divergence(&model)?.dt1t2)?;
+            pb.inc(1);
+        }
+        errors.push(error);
+    }
+    pb.finish();
+
+    let best = weights
+        .iter()
+        .zip(&errors)
+        .min_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(w, _)| *w)
+        .expect("No weights to choose from");
+    Ok(CrossValidation {
+        folds,
+        weights: weights.to_vec(),
+        errors,
+        best,
+    })
+}
+
+impl CrossValidation {
+    pub fn to_file(&self, path: &Path) -> std::io::Result<()> {
+        println!("Writing cross-validation to file: {}", path.display());
+        let mut file = File::create(path)?;
+        file.write_all(self.to_string().as_bytes())
+    }
+}
+
+impl Display for CrossValidation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Folds\t{}", self.folds)?;
+        writeln!(f, "Best\t{}", self.best)?;
+        writeln!(f, "\nEqPWeight\tHeldOutError")?;
+        for (weight, error) in self.weights.iter().zip(&self.errors) {
+            writeln!(f, "{weight}\t{error}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::structs::{Estimator, MatingSystem, ModelKind};
+
+    use super::*;
+
+    #[test]
+    fn chooses_one_of_the_weights() {
+        let (pedigree, p0uu, _) = Pedigree::example();
+        let problem = Problem::new(
+            pedigree,
+            p0uu,
+            p0uu,
+            1.0,
+            ModelKind::Neutral,
+            MatingSystem::Selfing,
+            Estimator::LeastSquares,
+        );
+
+        let cv = eqp_weight(&problem, &[0.0, 1.0], 3, 2, 0, OptimizerSettings::default())
+            .expect("Cross-validation failed");
+
+        assert_eq!(cv.errors.len(), 2);
+        assert!(cv.errors.iter().all(|e| e.is_finite()));
+        assert!(cv.weights.contains(&cv.best));
+        assert!(eqp_weight(&problem, &[1.0], 1, 2, 0, OptimizerSettings::default()).is_err());
+    }
+}
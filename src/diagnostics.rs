@@ -0,0 +1,287 @@
+use std::{fmt::Display, fs::File, io::Write, path::Path};
+
+use crate::{
+    optimizer::Minimum,
+    structs::{Model, Problem},
+};
+
+/// Starts whose rates and intercept differ by less than this fraction reached the same optimum
+const CLUSTER_RADIUS: f64 = 0.05;
+/// Same for the weight and selection coefficient, which are proportions and are compared absolutely, so that e.g. two weights near zero are equal
+const CLUSTER_PROPORTION: f64 = 0.01;
+/// Clusters whose least squares are within this fraction of the best one compete with it
+const COMPETING_COST: f64 = 0.01;
+/// Above this share of the cost, the equilibrium penalty rather than the divergences determines the fit
+const DOMINANT_PENALTY: f64 = 0.5;
+
+/// A single start of the multistart optimization
+#[derive(Debug, Clone)]
+pub struct Start {
+    pub model: Model,
+    /// Least squares of the fit
+    pub lse: f64,
+    pub iterations: u64,
+    pub termination: String,
+    pub converged: bool,
+    /// Index into [`Diagnostics::clusters`]
+    pub cluster: usize,
+}
+
+/// Starts that reached the same optimum
+#[derive(Debug, Clone)]
+pub struct Cluster {
+    /// Best model of the cluster
+    pub model: Model,
+    pub lse: f64,
+    pub size: usize,
+}
+
+/// How the starts of the multistart optimization ended up, to judge whether the best model can be trusted.
+///
+/// Starts are sorted by least squares, so the first one is the estimate, and so are the clusters.
+#[derive(Debug, Clone)]
+pub struct Diagnostics {
+    pub name: &'static str,
+    pub starts: Vec<Start>,
+    pub clusters: Vec<Cluster>,
+
+    /// Equilibrium proportion of unmethylated sites the penalty pulls towards, and its weight
+    pub eqp: f64,
+    pub eqp_weight: f64,
+    /// Equilibrium penalty of the best start, which the optimizer minimized on top of its least squares
+    pub penalty: f64,
+}
+
+impl Start {
+    pub fn new(model: Model, lse: f64, minimum: Minimum) -> Self {
+        Start {
+            model,
+            lse,
+            iterations: minimum.iterations,
+            termination: minimum.termination,
+            converged: minimum.converged,
+            cluster: 0,
+        }
+    }
+}
+
+impl Diagnostics {
+    /// Sort the starts and cluster them greedily: Every start joins the first cluster whose best model is close enough, or opens a new one.
+    pub fn new(problem: &Problem, mut starts: Vec<Start>) -> Self {
+        starts.sort_by(|a, b| a.lse.total_cmp(&b.lse));
+
+        let mut clusters: Vec<Cluster> = Vec::new();
+        for start in &mut starts {
+            let params = start.model.to_vec();
+            // Alpha, Beta, Weight, Intercept (, SelCoef)
+            let close = |cluster: &Cluster| {
+                let center = cluster.model.to_vec();
+                center.iter().zip(&params).enumerate().all(|(i, (a, b))| {
+                    if i == 2 || i == 4 {
+                        (a - b).abs() < CLUSTER_PROPORTION
+                    } else {
+                        // Parameters fixed at zero are the same everywhere
+                        (a - b).abs() <= CLUSTER_RADIUS * a.abs().max(b.abs())
+                    }
+                })
+            };
+            match clusters.iter().position(close) {
+                Some(i) => {
+                    clusters[i].size += 1;
+                    start.cluster = i;
+                }
+                None => {
+                    start.cluster = clusters.len();
+                    clusters.push(Cluster {
+                        model: start.model.clone(),
+                        lse: start.lse,
+                        size: 1,
+                    });
+                }
+            }
+        }
+
+        let penalty = starts
+            .first()
+            .and_then(|s| problem.penalty(&s.model).ok())
+            .unwrap_or(f64::NAN);
+        Diagnostics {
+            name: problem.kind.name(),
+            starts,
+            clusters,
+            eqp: problem.eqp,
+            eqp_weight: problem.eqp_weight,
+            penalty,
+        }
+    }
+
+    /// Share of the equilibrium penalty in the cost of the best start
+    pub fn penalty_share(&self) -> f64 {
+        let lse = self.starts.first().map_or(f64::NAN, |s| s.lse);
+        self.penalty / (lse + self.penalty)
+    }
+
+    /// Clusters other than the best one with about the same least squares: Different parameters explain the data equally well
+    pub fn competing(&self) -> &[Cluster] {
+        let Some(best) = self.clusters.first() else {
+            return &[];
+        };
+        let n = self
+            .clusters
+            .iter()
+            .skip(1)
+            .take_while(|c| c.lse <= best.lse * (1.0 + COMPETING_COST))
+            .count();
+        &self.clusters[1..=n]
+    }
+
+    pub fn n_converged(&self) -> usize {
+        self.starts.iter().filter(|s| s.converged).count()
+    }
+
+    /// Print a warning if the optimum is not well determined
+    pub fn warn(&self) {
+        let n = self.starts.len();
+        let converged = self.n_converged();
+        if converged < n {
+            println!(
+                "Warning: {} of {n} {} starts did not converge",
+                n - converged,
+                self.name
+            );
+        }
+        if let Some(best) = self.clusters.first() {
+            if best.size * 2 < n {
+                println!(
+                    "Warning: Only {} of {n} {} starts reached the best optimum",
+                    best.size, self.name
+                );
+            }
+        }
+        if self.penalty_share() > DOMINANT_PENALTY {
+            println!(
+                "Warning: The equilibrium penalty makes up {:.0}% of the cost of the best {} fit, consider a lower weight",
+                100.0 * self.penalty_share(),
+                self.name
+            );
+        }
+        let competing = self.competing();
+        if let Some(cluster) = competing.first() {
+            println!(
+                "Warning: {} other {} optima fit almost as well as the best one, the closest (least squares {} vs {}): {}",
+                competing.len(),
+                self.name,
+                cluster.lse,
+                self.clusters[0].lse,
+                cluster.model
+            );
+        }
+    }
+
+    pub fn to_file(&self, path: &Path) -> std::io::Result<()> {
+        println!("Writing multistart diagnostics to file: {}", path.display());
+        let mut file = File::create(path)?;
+        file.write_all(self.to_string().as_bytes())
+    }
+}
+
+impl Display for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Model\t{}", self.name)?;
+        writeln!(f, "Starts\t{}", self.starts.len())?;
+        writeln!(f, "Converged\t{}", self.n_converged())?;
+        writeln!(f, "Competing\t{}", self.competing().len())?;
+        writeln!(f, "EqP\t{}", self.eqp)?;
+        writeln!(f, "EqPWeight\t{}", self.eqp_weight)?;
+        writeln!(f, "Penalty\t{}", self.penalty)?;
+        writeln!(f, "PenaltyShare\t{}", self.penalty_share())?;
+
+        writeln!(
+            f,
+            "\nCluster\tStarts\tLSE\tAlpha\tBeta\tWeight\tIntercept\tSelCoef"
+        )?;
+        for (i, c) in self.clusters.iter().enumerate() {
+            let m = &c.model;
+            writeln!(
+                f,
+                "{i}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                c.size,
+                c.lse,
+                m.alpha,
+                m.beta,
+                m.weight,
+                m.intercept,
+                m.sel_coef.map_or("NA".to_string(), |s| s.to_string())
+            )?;
+        }
+
+        writeln!(
+            f,
+            "\nStart\tLSE\tIterations\tConverged\tTermination\tCluster\tAlpha\tBeta\tWeight\tIntercept\tSelCoef"
+        )?;
+        for (i, s) in self.starts.iter().enumerate() {
+            let m = &s.model;
+            writeln!(
+                f,
+                "{i}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                s.lse,
+                s.iterations,
+                s.converged,
+                s.termination,
+                s.cluster,
+                m.alpha,
+                m.beta,
+                m.weight,
+                m.intercept,
+                m.sel_coef.map_or("NA".to_string(), |s| s.to_string())
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{structs::Parameterization, *};
+
+    use super::*;
+
+    fn start(alpha: f64, lse: f64) -> Start {
+        let minimum = Minimum {
+            param: Vec::new(),
+            iterations: 100,
+            termination: "Converged".to_string(),
+            converged: true,
+        };
+        let model = Model {
+            alpha,
+            ..Model::default()
+        };
+        Start::new(model, lse, minimum)
+    }
+
+    #[test]
+    fn clusters_starts_and_finds_competing_optima() {
+        let problem = Problem {
+            parameterization: Parameterization::Transformed,
+            ..Problem::default()
+        };
+        let alpha = Model::default().alpha;
+        let diagnostics = Diagnostics::new(
+            &problem,
+            vec![
+                start(alpha * 1.001, 1.0001),
+                start(alpha * 3.0, 1.0005),
+                start(alpha, 1.0),
+                start(alpha * 10.0, 2.0),
+            ],
+        );
+
+        assert_eq!(diagnostics.clusters.len(), 3);
+        assert_eq!(diagnostics.clusters[0].size, 2);
+        assert_eq!(diagnostics.starts[0].lse, 1.0);
+        assert_eq!(diagnostics.starts[1].cluster, 0);
+        assert_eq!(diagnostics.competing().len(), 1);
+        assert_close!(diagnostics.competing()[0].model.alpha, alpha * 3.0);
+    }
+}
@@ -1,68 +1,299 @@
-use argmin_math::ArgminInv;
+use std::collections::HashMap;
 
 use ndarray::array;
 
-use ndarray::Array2;
+use ndarray::{Array1, Array2};
 
 use crate::alphabeta::p_uu_est;
+use crate::error::Error;
 use crate::pedigree::Pedigree;
 
+/// 3x3 matrix on the stack. The hot loop of the divergence uses these instead of `Array2`, which would allocate and go through BLAS for every product.
+pub type Mat3 = [[f64; 3]; 3];
+
+/// Transition matrices for the time spans of a pedigree, see [`time_spans`]
+pub type Powers = HashMap<u64, Mat3>;
+
 #[derive(Debug)]
 pub struct Divergence {
     pub dt1t2: Vec<f64>,
     pub p_uu: f64,
 }
+/// Raise a matrix to any non-negative power, e.g. the number of generations between two samples.
+///
+/// Whole powers are computed by repeated squaring. Fractional powers are only supported for 3x3 transition matrices
+/// with real, non-negative eigenvalues, see [`fractional_matrix_power`].
 // https://numpy.org/doc/stable/reference/generated/numpy.linalg.matrix_power.html
-pub fn matrix_power(matrix: &Array2<f64>, power: i8) -> Array2<f64> {
-    if power < 0 {
-        return matrix_power(&matrix.inv().unwrap(), power.abs());
+pub fn matrix_power(matrix: &Array2<f64>, power: f64) -> Result<Array2<f64>, Error> {
+    if !power.is_finite() || power < 0.0 {
+        return Err(Error::InvalidTime(power));
     }
 
-    if power == 0 {
-        // return identity matrix
-        return Array2::eye(matrix.nrows());
+    if power.fract() != 0.0 {
+        return fractional_matrix_power(matrix, power);
     }
-    let mut result = matrix.clone();
 
-    for _ in 1..power {
-        result = result.dot(matrix);
+    // Square the matrix for every bit of the power, and multiply the squares of the set bits
+    let mut result = Array2::eye(matrix.nrows());
+    let mut square = matrix.clone();
+    let mut remaining = power as u64;
+    while remaining > 0 {
+        if remaining & 1 == 1 {
+            result = result.dot(&square);
+        }
+        remaining >>= 1;
+        if remaining > 0 {
+            square = square.dot(&square);
+        }
     }
-    result
+    Ok(result)
+}
+
+/// Fractional power of a 3x3 transition matrix P.
+///
+/// With Q = P - I, whose eigenvalues are 0, mu_a and mu_b, P^t is the quadratic polynomial in Q that interpolates (1 + mu)^t at the eigenvalues (Newton form):
+///
+/// P^t = I + g\[0, mu_b\] Q + g\[0, mu_b, mu_a\] Q (Q - mu_b I)
+///
+/// Working with Q instead of P keeps the tiny epimutation rates accurate, and the interpolation also holds for repeated eigenvalues.
+fn fractional_matrix_power(matrix: &Array2<f64>, power: f64) -> Result<Array2<f64>, Error> {
+    let invalid = || Error::FractionalPower(power);
+    if matrix.dim() != (3, 3)
+        || matrix
+            .rows()
+            .into_iter()
+            .any(|r| (r.sum() - 1.0).abs() > 1e-9)
+    {
+        return Err(invalid());
+    }
+
+    // Rows of Q sum to zero, so its diagonal follows from the off-diagonal elements without cancellation
+    let mut q = matrix.clone();
+    for i in 0..3 {
+        q[[i, i]] = 0.0;
+        q[[i, i]] = -q.row(i).sum();
+    }
+
+    // The non-zero eigenvalues are the roots of x^2 - trace * x + minors
+    let trace = q[[0, 0]] + q[[1, 1]] + q[[2, 2]];
+    let minors = q[[0, 0]] * q[[1, 1]] - q[[0, 1]] * q[[1, 0]] + q[[0, 0]] * q[[2, 2]]
+        - q[[0, 2]] * q[[2, 0]]
+        + q[[1, 1]] * q[[2, 2]]
+        - q[[1, 2]] * q[[2, 1]];
+    let discriminant = trace.powi(2) - 4.0 * minors;
+    if discriminant < -1e-12 * trace.powi(2) {
+        return Err(invalid());
+    }
+    let root = (trace + trace.signum() * discriminant.max(0.0).sqrt()) / 2.0;
+    let (mu_a, mu_b) = if root == 0.0 {
+        (0.0, 0.0)
+    } else {
+        (root, minors / root)
+    };
+    if mu_a < -1.0 || mu_b < -1.0 {
+        return Err(invalid());
+    }
+
+    // g[0, mu] = ((1 + mu)^t - 1) / mu
+    let first = |mu: f64| {
+        if mu == 0.0 {
+            power
+        } else {
+            (power * mu.ln_1p()).exp_m1() / mu
+        }
+    };
+    let second = if (mu_a - mu_b).abs() > 1e-12 * mu_a.abs().max(mu_b.abs()) {
+        (first(mu_a) - first(mu_b)) / (mu_a - mu_b)
+    } else if mu_a == 0.0 {
+        power * (power - 1.0) / 2.0
+    } else {
+        // Derivative of g[0, mu] for (almost) repeated eigenvalues
+        let mu = mu_a;
+        (power * ((power - 1.0) * mu.ln_1p()).exp() - first(mu)) / mu
+    };
+
+    let shifted = &q - &(Array2::<f64>::eye(3) * mu_b);
+    Ok(Array2::eye(3) + &q * first(mu_b) + q.dot(&shifted) * second)
 }
 
 pub fn divergence(
     pedigree: &Pedigree,
     p_mm: f64,
-    _p_um: f64,
+    p_um: f64,
     p_uu: f64,
     alpha: f64,
     beta: f64,
     weight: f64,
-) -> Divergence {
+) -> Result<Divergence, Error> {
+    // 	Defining the generation (or transition) matrix
+    let genmatrix = genmatrix(alpha, beta);
+
+    let sv_gzero = founder_state(p_mm, p_um, p_uu, weight);
+    let powers = matrix_powers(&genmatrix, &time_spans(pedigree))?;
+    let dt1t2 = divergence_kernel(pedigree, &sv_gzero, &powers);
+
+    // Pr(UU) at equilibrium given alpha and beta
+    let p_uu = p_uu_est(alpha, beta);
+    Ok(Divergence { dt1t2, p_uu })
+}
+
+/// Same as [`divergence`], but for an arbitrary generation matrix and state vector at G0, e.g. one of the selection models or mating systems.
+///
+/// As there is no closed form for the equilibrium of these models, Pr(UU) at equilibrium is taken from the stationary distribution of the matrix.
+pub fn divergence_with_genmatrix(
+    pedigree: &Pedigree,
+    sv_gzero: &Array1<f64>,
+    genmatrix: &Array2<f64>,
+) -> Result<Divergence, Error> {
+    let powers = matrix_powers(genmatrix, &time_spans(pedigree))?;
+    let dt1t2 = divergence_kernel(pedigree, sv_gzero, &powers);
+    let p_uu = steady_state_distribution(genmatrix)[0];
+    Ok(Divergence { dt1t2, p_uu })
+}
+
+/// Divergence under the somatic model (ABsoma), where times are not generations but branch lengths in arbitrary units, e.g. years.
+///
+/// Times don't need to be whole numbers, as the transition matrix for any time span is known in closed form, see [`soma_matrix_power`].
+pub fn divergence_soma(
+    pedigree: &Pedigree,
+    p_mm: f64,
+    p_um: f64,
+    p_uu: f64,
+    alpha: f64,
+    beta: f64,
+    weight: f64,
+) -> Result<Divergence, Error> {
+    let sv_gzero = founder_state(p_mm, p_um, p_uu, weight);
+    let powers = time_spans(pedigree)
+        .into_iter()
+        .map(|t| Ok((key(t), mat3(&soma_matrix_power(alpha, beta, t)?))))
+        .collect::<Result<Powers, Error>>()?;
+    let dt1t2 = divergence_kernel(pedigree, &sv_gzero, &powers);
+    let p_uu = steady_state_distribution(&genmatrix_soma(alpha, beta))[0];
+    Ok(Divergence { dt1t2, p_uu })
+}
+
+/// State probabilities of a selfed founder at G0
+pub fn founder_state(p_mm: f64, _p_um: f64, p_uu: f64, weight: f64) -> Array1<f64> {
     // State probabilities at G0; first element = PrUU, second element = PrUM, third element = PrMM ### Is the second field correct?
+    array![p_uu, (weight) * p_mm, (1.0 - weight) * p_mm]
+}
 
-    let sv_gzero = array![p_uu, (weight) * p_mm, (1.0 - weight) * p_mm];
+/// Key of a time span in [`Powers`]
+fn key(t: f64) -> u64 {
+    // Adding zero turns -0.0 into 0.0
+    (t + 0.0).to_bits()
+}
 
-    // 	Defining the generation (or transition) matrix
-    let genmatrix = genmatrix(alpha, beta);
+/// The distinct time spans the divergence of a pedigree depends on: t0, t1 - t0 and t2 - t0 of every pair
+pub fn time_spans(pedigree: &Pedigree) -> Vec<f64> {
+    let mut times: Vec<f64> = pedigree
+        .rows()
+        .into_iter()
+        .flat_map(|p| [p[0], p[1] - p[0], p[2] - p[0]])
+        .collect();
+    times.sort_by(f64::total_cmp);
+    times.dedup_by_key(|t| key(*t));
+    times
+}
+
+/// Powers of `genmatrix` for all `times`, each computed only once.
+///
+/// Whole powers are built up from the next smaller one, multiplied by the power of the gap between them.
+/// The gaps are raised by repeated squaring, so large times cost a few products instead of one per generation.
+pub fn matrix_powers(genmatrix: &Array2<f64>, times: &[f64]) -> Result<Powers, Error> {
+    let mut powers = Powers::new();
+    let mut whole = Vec::new();
+    for &t in times {
+        if t.is_finite() && t > 0.0 && t.fract() == 0.0 {
+            whole.push(t);
+        } else {
+            powers.insert(key(t), mat3(&matrix_power(genmatrix, t)?));
+        }
+    }
+    whole.sort_by(f64::total_cmp);
+
+    let genmatrix = mat3(genmatrix);
+    let mut current = IDENTITY;
+    let mut generation = 0.0;
+    for t in whole {
+        current = mat3_mul(&current, &mat3_power(&genmatrix, (t - generation) as u64));
+        generation = t;
+        powers.insert(key(t), current);
+    }
+    Ok(powers)
+}
+
+const IDENTITY: Mat3 = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+/// Whole power of a 3x3 matrix by repeated squaring
+fn mat3_power(matrix: &Mat3, power: u64) -> Mat3 {
+    let mut result = IDENTITY;
+    let mut square = *matrix;
+    let mut remaining = power;
+    while remaining > 0 {
+        if remaining & 1 == 1 {
+            result = mat3_mul(&result, &square);
+        }
+        remaining >>= 1;
+        if remaining > 0 {
+            square = mat3_mul(&square, &square);
+        }
+    }
+    result
+}
 
-    let mut dt1t2 = Vec::new();
+pub fn mat3(matrix: &Array2<f64>) -> Mat3 {
+    let mut m = [[0.0; 3]; 3];
+    for ((i, j), x) in matrix.indexed_iter() {
+        m[i][j] = *x;
+    }
+    m
+}
+
+fn mat3_mul(a: &Mat3, b: &Mat3) -> Mat3 {
+    let mut m = [[0.0; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            m[i][j] = a[i][0] * b[0][j] + a[i][1] * b[1][j] + a[i][2] * b[2][j];
+        }
+    }
+    m
+}
+
+/// `powers` has to contain the transition matrices for all time spans of the pedigree, see [`time_spans`].
+///
+/// Pairs with the same times have the same divergence, which is only calculated once.
+fn divergence_kernel(pedigree: &Pedigree, sv_gzero: &Array1<f64>, powers: &Powers) -> Vec<f64> {
+    let power = |t: f64| &powers[&key(t)];
+    let sv_gzero = [sv_gzero[0], sv_gzero[1], sv_gzero[2]];
+    let mut by_times: HashMap<[u64; 3], f64> = HashMap::new();
+
+    let mut dt1t2 = Vec::with_capacity(pedigree.nrows());
     // 	Calculating theoretical divergence for every observed pair in 'data/data/pedigree.txt'
     for p in pedigree.rows() {
-        let (t0, t1, t2) = (p[0] as i8, p[1] as i8, p[2] as i8);
+        let (t0, t1, t2) = (p[0], p[1], p[2]);
+        let times = [key(t0), key(t1), key(t2)];
+        if let Some(d) = by_times.get(&times) {
+            dt1t2.push(*d);
+            continue;
+        }
 
         // 			Define state vectors for t1,t2 and t0 from pedigree using matrix multiplications from library(expm)
-        let svt0 = sv_gzero.t().dot(&matrix_power(&genmatrix, t0));
+        let p_t0 = power(t0);
+        let svt0: [f64; 3] = std::array::from_fn(|j| {
+            sv_gzero[0] * p_t0[0][j] + sv_gzero[1] * p_t0[1][j] + sv_gzero[2] * p_t0[2][j]
+        });
 
-        let t1t0 = matrix_power(&genmatrix, t1 - t0);
-        let t2t0 = matrix_power(&genmatrix, t2 - t0);
+        let t1t0 = power(t1 - t0);
+        let t2t0 = power(t2 - t0);
 
-        let svt1_mm = t1t0.row(2);
-        let svt2_mm = t2t0.row(2);
-        let svt1_um = t1t0.row(1);
-        let svt2_um = t2t0.row(1);
-        let svt1_uu = t1t0.row(0);
-        let svt2_uu = t2t0.row(0);
+        let svt1_mm = t1t0[2];
+        let svt2_mm = t2t0[2];
+        let svt1_um = t1t0[1];
+        let svt2_um = t2t0[1];
+        let svt1_uu = t1t0[0];
+        let svt2_uu = t2t0[0];
 
         // Conditional divergences
         let dt1t2_mm = 0.5_f64
@@ -86,11 +317,11 @@ pub fn divergence(
                 + svt1_uu[2] * svt2_uu[1])
             + (svt1_uu[0] * svt2_uu[2] + svt1_uu[2] * svt2_uu[0]);
 
-        dt1t2.push(svt0[0] * (dt1t2_uu) + svt0[1] * (dt1t2_um) + svt0[2] * (dt1t2_mm));
+        let d = svt0[0] * (dt1t2_uu) + svt0[1] * (dt1t2_um) + svt0[2] * (dt1t2_mm);
+        by_times.insert(times, d);
+        dt1t2.push(d);
     }
-    // Pr(UU) at equilibrium given alpha and beta
-    let p_uu = p_uu_est(alpha, beta);
-    Divergence { dt1t2, p_uu }
+    dt1t2
 }
 
 pub fn genmatrix(alpha: f64, beta: f64) -> Array2<f64> {
@@ -113,6 +344,106 @@ pub fn genmatrix(alpha: f64, beta: f64) -> Array2<f64> {
     ]
 }
 
+/// Generation matrix of a line that is crossed to a reference line in every generation.
+///
+/// One gamete comes from the line itself and epimutates as under selfing, the other one from the reference,
+/// whose plants are in the state distribution given by `p_mm`, `p_um` and `p_uu`.
+pub fn genmatrix_outcross(alpha: f64, beta: f64, p_mm: f64, p_um: f64, p_uu: f64) -> Array2<f64> {
+    // Probability of a methylated gamete from a plant in state UU, UM and MM
+    let gamete_m = [alpha, 0.5 * (alpha + 1.0 - beta), 1.0 - beta];
+    let reference_m = p_uu * gamete_m[0] + p_um * gamete_m[1] + p_mm * gamete_m[2];
+    let reference_u = 1.0 - reference_m;
+
+    let mut genmatrix = Array2::zeros((3, 3));
+    for (mut row, m) in genmatrix.rows_mut().into_iter().zip(gamete_m) {
+        let u = 1.0 - m;
+        row[0] = u * reference_u;
+        row[1] = u * reference_m + m * reference_u;
+        row[2] = m * reference_m;
+    }
+    genmatrix
+}
+
+/// Generation matrix of ABselectMM: The neutral transitions, but offspring in the MM state only survive with probability `1 - sel_coef`.
+///
+/// Each row is renormalised, so the matrix stays a valid transition matrix.
+pub fn genmatrix_select_mm(alpha: f64, beta: f64, sel_coef: f64) -> Array2<f64> {
+    select(genmatrix(alpha, beta), [1.0, 1.0, 1.0 - sel_coef])
+}
+
+/// Generation matrix of ABselectUU: The neutral transitions, but offspring in the UU state only survive with probability `1 - sel_coef`.
+///
+/// Each row is renormalised, so the matrix stays a valid transition matrix.
+pub fn genmatrix_select_uu(alpha: f64, beta: f64, sel_coef: f64) -> Array2<f64> {
+    select(genmatrix(alpha, beta), [1.0 - sel_coef, 1.0, 1.0])
+}
+
+/// Transition matrix of the somatic model (ABsoma) for one time unit.
+///
+/// In contrast to the germline models, there is no segregation: Both alleles of a cell gain (`alpha`) or lose (`beta`) methylation independently.
+pub fn genmatrix_soma(alpha: f64, beta: f64) -> Array2<f64> {
+    array![
+        [
+            (1.0 - alpha).powi(2),
+            2.0 * (1.0 - alpha) * alpha,
+            alpha.powi(2)
+        ],
+        [
+            beta * (1.0 - alpha),
+            (1.0 - alpha) * (1.0 - beta) + alpha * beta,
+            alpha * (1.0 - beta)
+        ],
+        [
+            beta.powi(2),
+            2.0 * (1.0 - beta) * beta,
+            (1.0 - beta).powi(2)
+        ]
+    ]
+}
+
+/// Transition matrix of the somatic model over `t` time units, where `t` can be any non-negative number.
+///
+/// A single allele switches with the rates of a two-state chain, whose `t`-th power is known in closed form.
+/// The somatic matrix is built from the allele rates in the same way, so `genmatrix_soma` applied to the accumulated rates gives the matrix power.
+pub fn soma_matrix_power(alpha: f64, beta: f64, t: f64) -> Result<Array2<f64>, Error> {
+    if !t.is_finite() || t < 0.0 {
+        return Err(Error::InvalidTime(t));
+    }
+    if alpha + beta == 0.0 {
+        return Ok(Array2::eye(3));
+    }
+    // 1 - (1 - alpha - beta)^t, written to stay accurate for tiny rates
+    let switched = -(t * (-(alpha + beta)).ln_1p()).exp_m1();
+    Ok(genmatrix_soma(
+        alpha / (alpha + beta) * switched,
+        beta / (alpha + beta) * switched,
+    ))
+}
+
+/// Weight the columns of a generation matrix by the relative fitness of the offspring state (UU, UM, MM)
+pub fn select(mut genmatrix: Array2<f64>, fitness: [f64; 3]) -> Array2<f64> {
+    for mut row in genmatrix.rows_mut() {
+        for (p, w) in row.iter_mut().zip(fitness) {
+            *p *= w;
+        }
+        let sum = row.sum();
+        row /= sum;
+    }
+    genmatrix
+}
+
+/// Stationary distribution (UU, UM, MM) of a 3x3 generation matrix.
+///
+/// Uses the Markov chain tree theorem, which only sums up positive products and therefore stays accurate for the tiny rates we are dealing with.
+pub fn steady_state_distribution(genmatrix: &Array2<f64>) -> [f64; 3] {
+    let p = |i: usize, j: usize| genmatrix[[i, j]];
+    let uu = p(1, 0) * p(2, 0) + p(1, 0) * p(2, 1) + p(1, 2) * p(2, 0);
+    let um = p(0, 1) * p(2, 1) + p(0, 1) * p(2, 0) + p(0, 2) * p(2, 1);
+    let mm = p(0, 2) * p(1, 2) + p(0, 2) * p(1, 0) + p(0, 1) * p(1, 2);
+    let total = uu + um + mm;
+    [uu / total, um / total, mm / total]
+}
+
 #[cfg(test)]
 mod test {
     use crate::*;
@@ -129,7 +460,7 @@ mod test {
     #[test]
     fn test_matrix_power_is_identity_when_power_is_zero() {
         let m = array![[1.0, 2.0, 3.0], [4.0, 5.0, 6.0], [7.0, 8.0, 9.0]];
-        let m_power = matrix_power(&m, 0);
+        let m_power = matrix_power(&m, 0.0).unwrap();
         assert_eq!(
             m_power,
             array![[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
@@ -146,7 +477,8 @@ mod test {
             3.974271e-09,
             1.519045e-07,
             0.06892953,
-        );
+        )
+        .unwrap();
 
         let r = include_str!("../data/divergence.txt")
             .split('\n')
@@ -160,45 +492,200 @@ mod test {
         }
     }
 
+    #[test]
+    fn steady_state_distribution_matches_closed_form() {
+        let (alpha, beta) = (2.298873e-04, 0.0017179248);
+        let steady_state = steady_state_distribution(&genmatrix(alpha, beta));
+        assert_close!(steady_state[0], p_uu_est(alpha, beta));
+        assert_close!(steady_state.iter().sum::<f64>(), 1.0);
+    }
+
+    #[test]
+    fn selection_without_coefficient_is_neutral() {
+        let neutral = genmatrix(0.2, 0.5);
+        for (a, b) in genmatrix_select_mm(0.2, 0.5, 0.0)
+            .iter()
+            .zip(neutral.iter())
+        {
+            assert_close!(a, b);
+        }
+        for (a, b) in genmatrix_select_uu(0.2, 0.5, 0.0)
+            .iter()
+            .zip(neutral.iter())
+        {
+            assert_close!(a, b);
+        }
+
+        let selected = genmatrix_select_mm(0.2, 0.5, 0.3);
+        for row in selected.rows() {
+            assert_close!(row.sum(), 1.0);
+        }
+        // Selection against MM lowers the equilibrium share of MM
+        assert!(steady_state_distribution(&selected)[2] < steady_state_distribution(&neutral)[2]);
+    }
+
+    #[test]
+    fn outcrossing_to_unmethylated_reference() {
+        let genmatrix = genmatrix_outcross(0.01, 0.02, 0.0, 0.0, 1.0);
+        for row in genmatrix.rows() {
+            assert_close!(row.sum(), 1.0);
+            // The reference never contributes a methylated gamete without epimutation
+            assert!(row[2] < 0.01);
+        }
+        // Without epimutations, an unmethylated line stays unmethylated
+        let genmatrix = genmatrix_outcross(0.0, 0.0, 0.0, 0.0, 1.0);
+        assert_eq!(genmatrix.row(0).to_vec(), vec![1.0, 0.0, 0.0]);
+        assert_eq!(genmatrix.row(2).to_vec(), vec![0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn soma_power_is_matrix_power() {
+        let (alpha, beta) = (0.02, 0.05);
+        let genmatrix = genmatrix_soma(alpha, beta);
+        for t in 0..5 {
+            let closed_form = soma_matrix_power(alpha, beta, t as f64).unwrap();
+            let power = matrix_power(&genmatrix, t as f64).unwrap();
+            for (a, b) in closed_form.iter().zip(power.iter()) {
+                assert_close!(a, b);
+            }
+        }
+        // Half a time unit twice is a full time unit
+        let half = soma_matrix_power(alpha, beta, 0.5).unwrap();
+        for (a, b) in half.dot(&half).iter().zip(genmatrix.iter()) {
+            assert_close!(a, b);
+        }
+    }
+
+    #[test]
+    fn fractional_power_is_consistent() {
+        for genmatrix in [genmatrix(2.298873e-04, 0.0017179248), genmatrix(0.2, 0.5)] {
+            // Agrees with repeated multiplication for whole powers
+            let eigen = fractional_matrix_power(&genmatrix, 3.0).unwrap();
+            for (a, b) in eigen
+                .iter()
+                .zip(matrix_power(&genmatrix, 3.0).unwrap().iter())
+            {
+                assert_close!(a, b);
+            }
+            // Two half generations are one generation
+            let half = matrix_power(&genmatrix, 0.5).unwrap();
+            for (a, b) in half.dot(&half).iter().zip(genmatrix.iter()) {
+                assert_close!(a, b);
+            }
+        }
+        // Also for the somatic model, where the closed form is known
+        let (alpha, beta) = (0.02, 0.05);
+        let eigen = matrix_power(&genmatrix_soma(alpha, beta), 2.5).unwrap();
+        for (a, b) in eigen
+            .iter()
+            .zip(soma_matrix_power(alpha, beta, 2.5).unwrap().iter())
+        {
+            assert_close!(a, b);
+        }
+    }
+
+    #[test]
+    fn deep_and_invalid_times() {
+        let genmatrix = genmatrix(2.298873e-04, 0.0017179248);
+        // Used to wrap around at 128 generations
+        let deep = matrix_power(&genmatrix, 200.0).unwrap();
+        let shallow = matrix_power(&genmatrix, 100.0).unwrap();
+        for (a, b) in deep.iter().zip(shallow.dot(&shallow).iter()) {
+            assert_close!(a, b);
+        }
+
+        assert!(matches!(
+            matrix_power(&genmatrix, -1.0),
+            Err(Error::InvalidTime(_))
+        ));
+        assert!(matches!(
+            matrix_power(&genmatrix, f64::NAN),
+            Err(Error::InvalidTime(_))
+        ));
+    }
+
+    #[test]
+    fn cached_powers_are_matrix_powers() {
+        let genmatrix = genmatrix(0.2, 0.5);
+        let times = [0.0, 1.0, 2.5, 3.0, 7.0];
+        let powers = matrix_powers(&genmatrix, &times).unwrap();
+        assert_eq!(powers.len(), times.len());
+        for t in times {
+            for (a, b) in powers[&key(t)]
+                .iter()
+                .flatten()
+                .zip(matrix_power(&genmatrix, t).unwrap().iter())
+            {
+                assert_close!(a, b);
+            }
+        }
+        assert!(matches!(
+            matrix_powers(&genmatrix, &[1.0, -2.0]),
+            Err(Error::InvalidTime(_))
+        ));
+    }
+
+    #[test]
+    fn large_powers_by_squaring() {
+        let genmatrix = genmatrix(1e-4, 5e-4);
+        let times = [1.0, 150.0, 1000.0, 100_000.0];
+        let powers = matrix_powers(&genmatrix, &times).unwrap();
+        for t in times {
+            // The closed form of the fractional powers holds for whole ones as well
+            let closed_form = fractional_matrix_power(&genmatrix, t).unwrap();
+            let squared = matrix_power(&genmatrix, t).unwrap();
+            for ((a, b), c) in powers[&key(t)]
+                .iter()
+                .flatten()
+                .zip(squared.iter())
+                .zip(closed_form.iter())
+            {
+                assert!((a - c).abs() < 1e-9 && (b - c).abs() < 1e-9);
+            }
+        }
+    }
+
     #[test]
     fn dot_product_is_the_same_as_row() {
         let genmatrix = genmatrix(0.2, 0.5);
-        let dot = array![0.0, 0.0, 1.0].t().dot(&matrix_power(&genmatrix, 2));
+        let dot = array![0.0, 0.0, 1.0]
+            .t()
+            .dot(&matrix_power(&genmatrix, 2.0).unwrap());
 
-        let row = matrix_power(&genmatrix, 2).row(2).to_owned();
+        let row = matrix_power(&genmatrix, 2.0).unwrap().row(2).to_owned();
         assert_eq!(dot, row);
     }
     #[test]
     fn implementation_stays_same() {
         let genmatrix = genmatrix(0.2, 0.5);
-        let t2 = 2;
-        let t1 = 1;
-        let t0 = 0;
+        let t2 = 2.0;
+        let t1 = 1.0;
+        let t0 = 0.0;
         let svt1_mm = array![0.0, 0.0, 1.0]
             .t()
-            .dot(&matrix_power(&genmatrix, t1 - t0));
+            .dot(&matrix_power(&genmatrix, t1 - t0).unwrap());
         let svt2_mm = array![0.0, 0.0, 1.0]
             .t()
-            .dot(&matrix_power(&genmatrix, t2 - t0));
+            .dot(&matrix_power(&genmatrix, t2 - t0).unwrap());
         let svt1_um = array![0.0, 1.0, 0.0]
             .t()
-            .dot(&matrix_power(&genmatrix, t1 - t0));
+            .dot(&matrix_power(&genmatrix, t1 - t0).unwrap());
         let svt2_um = array![0.0, 1.0, 0.0]
             .t()
-            .dot(&matrix_power(&genmatrix, t2 - t0));
+            .dot(&matrix_power(&genmatrix, t2 - t0).unwrap());
         let svt1_uu = array![1.0, 0.0, 0.0]
             .t()
-            .dot(&matrix_power(&genmatrix, t1 - t0));
+            .dot(&matrix_power(&genmatrix, t1 - t0).unwrap());
         let svt2_uu = array![1.0, 0.0, 0.0]
             .t()
-            .dot(&matrix_power(&genmatrix, t2 - t0));
-
-        let svt1_mm_row = &matrix_power(&genmatrix, t1 - t0).row(2).to_owned();
-        let svt2_mm_row = &matrix_power(&genmatrix, t2 - t0).row(2).to_owned();
-        let svt1_um_row = &matrix_power(&genmatrix, t1 - t0).row(1).to_owned();
-        let svt2_um_row = &matrix_power(&genmatrix, t2 - t0).row(1).to_owned();
-        let svt1_uu_row = &matrix_power(&genmatrix, t1 - t0).row(0).to_owned();
-        let svt2_uu_row = &matrix_power(&genmatrix, t2 - t0).row(0).to_owned();
+            .dot(&matrix_power(&genmatrix, t2 - t0).unwrap());
+
+        let svt1_mm_row = &matrix_power(&genmatrix, t1 - t0).unwrap().row(2).to_owned();
+        let svt2_mm_row = &matrix_power(&genmatrix, t2 - t0).unwrap().row(2).to_owned();
+        let svt1_um_row = &matrix_power(&genmatrix, t1 - t0).unwrap().row(1).to_owned();
+        let svt2_um_row = &matrix_power(&genmatrix, t2 - t0).unwrap().row(1).to_owned();
+        let svt1_uu_row = &matrix_power(&genmatrix, t1 - t0).unwrap().row(0).to_owned();
+        let svt2_uu_row = &matrix_power(&genmatrix, t2 - t0).unwrap().row(0).to_owned();
 
         assert_eq!(svt1_mm, svt1_mm_row);
         assert_eq!(svt2_mm, svt2_mm_row);
@@ -25,4 +25,10 @@ pub enum Error {
 
     #[error("Chromosome could not be parsed from this string: {0}")]
     Chromosome(String),
+
+    #[error("Invalid time span in pedigree: {0}. Times must be finite, non-negative and not earlier than the last common ancestor")]
+    InvalidTime(f64),
+
+    #[error("Unable to raise the generation matrix to the fractional power {0}: It has negative or complex eigenvalues")]
+    FractionalPower(f64),
 }
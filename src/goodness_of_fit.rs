@@ -0,0 +1,213 @@
+use std::{fmt::Display, fs::File, io::Write, path::Path};
+
+use ndarray::{Array1, Array2};
+use statrs::distribution::{ChiSquared, ContinuousCDF};
+
+use crate::{
+    pedigree::Pedigree,
+    structs::{Model, ModelKind, PredictedDivergence, Problem, Residuals},
+    *,
+};
+
+/// Number of points of the theoretical divergence curve
+const CURVE_POINTS: usize = 100;
+
+/// How well the best model fits the pedigree, like the R package's ABplot
+#[derive(Debug, Clone)]
+pub struct GoodnessOfFit {
+    /// Share of the variance of the observed divergence explained by the model
+    pub r_squared: f64,
+    pub rmse: f64,
+    /// Slope of the residuals over delta t, zero if the model captures how the divergence grows
+    pub residual_trend: f64,
+    pub skewness: f64,
+    /// Excess kurtosis of the residuals, zero for normal ones
+    pub kurtosis: f64,
+    /// Jarque-Bera test of normal residuals
+    pub jarque_bera: f64,
+    pub p_value: f64,
+
+    /// Delta t, observed divergence, predicted divergence and residual of every pair
+    pub pairs: Vec<(f64, f64, f64, f64)>,
+    /// Theoretical divergence over delta t, between the founder generation and a descendant delta t later
+    pub curve: Vec<(f64, f64)>,
+}
+
+impl GoodnessOfFit {
+    /// Judge the fit of the `best` model to the pedigree of `problem`, with the predicted divergence and residuals of the estimation
+    pub fn new(
+        problem: &Problem,
+        best: &Model,
+        predicted: &PredictedDivergence,
+        residuals: &Residuals,
+    ) -> Result<Self, Error> {
+        let observed = problem.pedigree.column(3);
+        let n = residuals.len() as f64;
+        let delta_t = problem.pedigree.delta_t();
+
+        let mean = observed.sum() / n;
+        let total = observed.iter().map(|d| (d - mean).powi(2)).sum::<f64>();
+        let rss = residuals.iter().map(|r| r.powi(2)).sum::<f64>();
+
+        // Least squares slope of the residuals over delta t
+        let mean_t = delta_t.iter().sum::<f64>() / n;
+        let mean_r = residuals.iter().sum::<f64>() / n;
+        let covariance = delta_t
+            .iter()
+            .zip(residuals)
+            .map(|(t, r)| (t - mean_t) * (r - mean_r))
+            .sum::<f64>();
+        let variance = delta_t.iter().map(|t| (t - mean_t).powi(2)).sum::<f64>();
+
+        // Moments of the residuals for the Jarque-Bera test
+        let moment = |k: i32| residuals.iter().map(|r| (r - mean_r).powi(k)).sum::<f64>() / n;
+        let skewness = moment(3) / moment(2).powf(1.5);
+        let kurtosis = moment(4) / moment(2).powi(2) - 3.0;
+        let jarque_bera = n / 6.0 * (skewness.powi(2) + kurtosis.powi(2) / 4.0);
+        let p_value = match ChiSquared::new(2.0) {
+            Ok(chi) if jarque_bera.is_finite() => 1.0 - chi.cdf(jarque_bera),
+            _ => f64::NAN,
+        };
+
+        let pairs = (0..residuals.len())
+            .map(|i| (delta_t[i], observed[i], predicted[i], residuals[i]))
+            .collect();
+
+        Ok(GoodnessOfFit {
+            r_squared: 1.0 - rss / total,
+            rmse: (rss / n).sqrt(),
+            residual_trend: covariance / variance,
+            skewness,
+            kurtosis,
+            jarque_bera,
+            p_value,
+            pairs,
+            curve: curve(problem, best, delta_t.iter().cloned().fold(0.0, f64::max))?,
+        })
+    }
+
+    pub fn to_file(&self, path: &Path) -> std::io::Result<()> {
+        println!("Writing goodness of fit to file: {}", path.display());
+        let mut file = File::create(path)?;
+        file.write_all(self.to_string().as_bytes())
+    }
+}
+
+/// Theoretical divergence of the model between the founder generation and a descendant, from delta t zero to `max`.
+///
+/// The generational models take whole generations, the somatic model any time.
+fn curve(problem: &Problem, best: &Model, max: f64) -> Result<Vec<(f64, f64)>, Error> {
+    let (step, max) = match problem.kind {
+        ModelKind::Soma => (max / (CURVE_POINTS - 1) as f64, max),
+        _ => (
+            (max / (CURVE_POINTS - 1) as f64).ceil().max(1.0),
+            max.round(),
+        ),
+    };
+    let mut times: Vec<f64> = (0..CURVE_POINTS - 1)
+        .map(|i| i as f64 * step)
+        .take_while(|t| *t < max)
+        .collect();
+    // The curve ends at the largest delta t, even if the steps don't reach it exactly
+    times.push(max);
+
+    // Pairs of a founder and its descendants, which have no generations in common
+    let mut pedigree = Array2::<f64>::zeros((times.len(), 4));
+    pedigree.column_mut(2).assign(&Array1::from(times.clone()));
+    let curve = Problem {
+        pedigree: Pedigree::from(pedigree),
+        ..problem.clone()
+    };
+    let divergence = curve.divergence(best)?;
+    Ok(times
+        .into_iter()
+        .zip(divergence.dt1t2)
+        .map(|(t, d)| (t, best.intercept + d))
+        .collect())
+}
+
+impl Display for GoodnessOfFit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "RSquared\t{}", self.r_squared)?;
+        writeln!(f, "RMSE\t{}", self.rmse)?;
+        writeln!(f, "ResidualTrend\t{}", self.residual_trend)?;
+        writeln!(f, "Skewness\t{}", self.skewness)?;
+        writeln!(f, "ExcessKurtosis\t{}", self.kurtosis)?;
+        writeln!(f, "JarqueBera\t{}", self.jarque_bera)?;
+        writeln!(f, "JarqueBeraPValue\t{}", self.p_value)?;
+
+        writeln!(f, "\nDeltaT\tObserved\tPredicted\tResidual")?;
+        for (delta_t, observed, predicted, residual) in &self.pairs {
+            writeln!(f, "{delta_t}\t{observed}\t{predicted}\t{residual}")?;
+        }
+
+        writeln!(f, "\nDeltaT\tTheoretical")?;
+        for (delta_t, divergence) in &self.curve {
+            writeln!(f, "{delta_t}\t{divergence}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn perfect_fit() {
+        let problem = Problem::default();
+        let model = Model::default();
+        let predicted: Vec<f64> = problem
+            .divergence(&model)
+            .unwrap()
+            .dt1t2
+            .iter()
+            .map(|d| model.intercept + d)
+            .collect();
+        // Observe exactly what the model predicts, up to tiny symmetric deviations
+        let residuals: Vec<f64> = (0..predicted.len())
+            .map(|i| if i % 2 == 0 { 1e-9 } else { -1e-9 })
+            .collect();
+        let mut pedigree = problem.pedigree.clone();
+        for (i, (p, r)) in predicted.iter().zip(&residuals).enumerate() {
+            pedigree[[i, 3]] = p + r;
+        }
+        let problem = Problem {
+            pedigree,
+            ..problem
+        };
+
+        let fit = GoodnessOfFit::new(&problem, &model, &predicted, &residuals).unwrap();
+        assert!(fit.r_squared > 0.999);
+        assert!(fit.rmse < 1e-8);
+
+        // The curve starts at the intercept and grows with the time
+        assert_close!(fit.curve[0].1, model.intercept);
+        assert!(fit.curve.windows(2).all(|w| w[0].1 <= w[1].1));
+        assert_eq!(
+            fit.curve.last().unwrap().0,
+            fit.pairs.iter().map(|p| p.0).fold(0.0, f64::max)
+        );
+    }
+
+    #[test]
+    fn curve_ends_at_the_largest_delta_t() {
+        let problem = Problem::default();
+        let model = Model::default();
+
+        // 150 generations don't divide into steps of two
+        let generations = curve(&problem, &model, 150.0).unwrap();
+        assert_eq!(generations.last().unwrap().0, 150.0);
+        assert!(generations.len() <= CURVE_POINTS);
+        assert!(generations.windows(2).all(|w| w[0].0 < w[1].0));
+
+        let problem = Problem {
+            kind: ModelKind::Soma,
+            ..problem
+        };
+        let times = curve(&problem, &model, 150.3).unwrap();
+        assert_eq!(times.len(), CURVE_POINTS);
+        assert_close!(times.last().unwrap().0, 150.3);
+        assert!(times.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+}
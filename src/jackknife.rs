@@ -0,0 +1,260 @@
+use std::{fmt::Display, fs::File, io::Write, path::Path};
+
+use indicatif::ProgressBar;
+use ndarray::Axis;
+
+use crate::{
+    ab_neutral::estimate,
+    optimizer::OptimizerSettings,
+    pedigree::{Genealogy, Pedigree},
+    structs::{Model, Problem},
+    *,
+};
+
+/// Fit without one of the samples
+#[derive(Debug, Clone)]
+pub struct Influence {
+    /// Name of the dropped node in the nodelist
+    pub sample: String,
+    /// Number of pairs left in the pedigree
+    pub pairs: usize,
+    pub model: Model,
+}
+
+/// Leave-one-sample-out jackknife of a fitted model.
+///
+/// Shows how much every single sample drives the estimates, which a bootstrap over a handful of samples can't.
+#[derive(Debug, Clone)]
+pub struct Jackknife {
+    pub full: Model,
+    pub samples: Vec<Influence>,
+    /// Names of the samples without which no pair is left to fit, so that they have no replicate
+    pub skipped: Vec<String>,
+
+    pub se_alpha: f64,
+    pub se_beta: f64,
+    pub se_weight: f64,
+    pub se_intercept: f64,
+    /// Only present for the selection models
+    pub se_sel_coef: Option<f64>,
+}
+
+/// Refit the model of `problem` once for every sample of the `genealogy`, leaving out all pairs with that sample.
+///
+/// The proportion of unmethylated sites at G0 is recomputed from the remaining samples, like when building the pedigree without it.
+/// So is the target of the equilibrium penalty, unless the user gave one as `eqp`.
+/// Samples that are part of every pair leave nothing to fit and are skipped.
+// The number of arguments is not ideal but it is just a complex function
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    problem: &Problem,
+    full: &Model,
+    genealogy: &Genealogy,
+    eqp: Option<f64>,
+    n_starts: usize,
+    seed: u64,
+    optimizer: OptimizerSettings,
+    pb: Option<&ProgressBar>,
+) -> Result<Jackknife, Box<dyn std::error::Error>> {
+    let samples = genealogy.samples();
+    if samples.len() < 3 {
+        return Err(Error::Simple("The jackknife needs at least three samples").into());
+    }
+    if genealogy.pairs.len() != problem.pedigree.nrows() {
+        return Err(Error::Simple("The genealogy does not belong to the pedigree").into());
+    }
+
+    let mut influences = Vec::with_capacity(samples.len());
+    let mut skipped = Vec::new();
+    for &sample in &samples {
+        let rows: Vec<usize> = genealogy
+            .pairs
+            .iter()
+            .enumerate()
+            .filter(|(_, (a, b))| *a != sample && *b != sample)
+            .map(|(row, _)| row)
+            .collect();
+        if rows.is_empty() {
+            println!(
+                "Warning: No pair is left without sample {}, the jackknife skips it",
+                genealogy.names[sample]
+            );
+            skipped.push(genealogy.names[sample].clone());
+            continue;
+        }
+        let pedigree = Pedigree::from(problem.pedigree.select(Axis(0), &rows));
+
+        let remaining: Vec<f64> = samples
+            .iter()
+            .filter(|s| **s != sample)
+            .filter_map(|s| genealogy.unmethylated[*s])
+            .collect();
+        let p0uu = remaining.iter().sum::<f64>() / remaining.len() as f64;
+
+        let reduced = Problem {
+            fixed: problem.fixed,
+            ..Problem::new(
+                pedigree,
+                p0uu,
+                eqp.unwrap_or(p0uu),
+                problem.eqp_weight,
+                problem.kind,
+                problem.mating,
+                problem.estimator,
+            )
+        };
+        let (model, _, _, _) = estimate(&reduced, n_starts, seed, optimizer, pb)?;
+        influences.push(Influence {
+            sample: genealogy.names[sample].clone(),
+            pairs: rows.len(),
+            model,
+        });
+    }
+
+    if influences.len() < 2 {
+        return Err(Error::Simple(
+            "The jackknife needs at least two samples that leave pairs to fit",
+        )
+        .into());
+    }
+
+    Ok(Jackknife::new(full.clone(), influences, skipped))
+}
+
+impl Jackknife {
+    pub fn new(full: Model, samples: Vec<Influence>, skipped: Vec<String>) -> Self {
+        let n = samples.len() as f64;
+        // sqrt((n - 1) / n * sum((x_i - mean)^2))
+        let se = |value: fn(&Model) -> f64| {
+            let values: Vec<f64> = samples.iter().map(|s| value(&s.model)).collect();
+            let mean = values.iter().sum::<f64>() / n;
+            ((n - 1.0) / n * values.iter().map(|v| (v - mean).powi(2)).sum::<f64>()).sqrt()
+        };
+
+        Jackknife {
+            se_alpha: se(|m| m.alpha),
+            se_beta: se(|m| m.beta),
+            se_weight: se(|m| m.weight),
+            se_intercept: se(|m| m.intercept),
+            se_sel_coef: full
+                .sel_coef
+                .map(|_| se(|m| m.sel_coef.unwrap_or(f64::NAN))),
+            full,
+            samples,
+            skipped,
+        }
+    }
+
+    pub fn to_file(&self, path: &Path) -> std::io::Result<()> {
+        println!("Writing jackknife to file: {}", path.display());
+        let mut file = File::create(path)?;
+        file.write_all(self.to_string().as_bytes())
+    }
+}
+
+impl Display for Jackknife {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "SEAlpha\t{}", self.se_alpha)?;
+        writeln!(f, "SEBeta\t{}", self.se_beta)?;
+        writeln!(f, "SEWeight\t{}", self.se_weight)?;
+        writeln!(f, "SEIntercept\t{}", self.se_intercept)?;
+        if let Some(se) = self.se_sel_coef {
+            writeln!(f, "SESelCoef\t{se}")?;
+        }
+        if !self.skipped.is_empty() {
+            writeln!(f, "Skipped\t{}", self.skipped.join(","))?;
+        }
+
+        // Relative change of the estimates when the sample is left out
+        writeln!(
+            f,
+            "\nSample\tPairs\tAlpha\tBeta\tWeight\tIntercept\tSelCoef\tChangeAlpha\tChangeBeta"
+        )?;
+        for s in &self.samples {
+            let m = &s.model;
+            writeln!(
+                f,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                s.sample,
+                s.pairs,
+                m.alpha,
+                m.beta,
+                m.weight,
+                m.intercept,
+                m.sel_coef.map_or("NA".to_string(), |s| s.to_string()),
+                m.alpha / self.full.alpha - 1.0,
+                m.beta / self.full.beta - 1.0,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::structs::ModelKind;
+
+    use super::*;
+
+    #[test]
+    fn samples_in_every_pair_are_skipped() {
+        let (pedigree, p0uu, mut genealogy) = Pedigree::example();
+        // Only the pairs of the first sample with the others
+        let first = genealogy.samples()[0];
+        let rows: Vec<usize> = (0..genealogy.pairs.len())
+            .filter(|row| genealogy.pairs[*row].0 == first || genealogy.pairs[*row].1 == first)
+            .collect();
+        genealogy.pairs = rows.iter().map(|row| genealogy.pairs[*row]).collect();
+        let problem = Problem::new(
+            Pedigree::from(pedigree.select(Axis(0), &rows)),
+            p0uu,
+            p0uu,
+            1.0,
+            ModelKind::Neutral,
+            Default::default(),
+            Default::default(),
+        );
+
+        let jackknife = run(
+            &problem,
+            &Model::default(),
+            &genealogy,
+            None,
+            2,
+            0,
+            Default::default(),
+            None,
+        )
+        .expect("Jackknife failed");
+        assert_eq!(jackknife.skipped, vec![genealogy.names[first].clone()]);
+        assert_eq!(jackknife.samples.len(), genealogy.samples().len() - 1);
+    }
+
+    #[test]
+    fn standard_error_of_the_mean() {
+        // For the mean, the jackknife standard error is the usual one
+        let alphas = [0.1, 0.2, 0.4, 0.5];
+        let n = alphas.len() as f64;
+        let samples = alphas
+            .iter()
+            .map(|alpha| {
+                let rest = alphas.iter().filter(|a| *a != alpha).sum::<f64>() / (n - 1.0);
+                Influence {
+                    sample: alpha.to_string(),
+                    pairs: 3,
+                    model: Model {
+                        alpha: rest,
+                        ..Model::default()
+                    },
+                }
+            })
+            .collect();
+        let jackknife = Jackknife::new(Model::default(), samples, Vec::new());
+
+        let mean = alphas.iter().sum::<f64>() / n;
+        let sd = (alphas.iter().map(|a| (a - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt();
+        assert_close!(jackknife.se_alpha, sd / n.sqrt());
+        assert_close!(jackknife.se_beta, 0.0);
+        assert!(jackknife.se_sel_coef.is_none());
+    }
+}
@@ -1,22 +1,36 @@
 pub mod ab_neutral;
+pub mod ab_null;
+pub mod ab_select;
+pub mod ab_soma;
 pub mod alphabeta;
 pub mod arguments;
 pub mod boot_model;
+pub mod comparison;
+pub mod cross_validation;
+pub mod diagnostics;
 pub mod divergence;
 pub mod error;
 pub mod extract;
 pub mod files;
 pub mod genes;
+pub mod goodness_of_fit;
+pub mod jackknife;
+pub mod likelihood;
 pub mod macros;
+pub mod mcmc;
 pub mod methylation_site;
+pub mod misclassification;
+pub mod optimizer;
 pub mod pedigree;
 pub mod plot;
+pub mod profile;
 pub mod progress;
 pub mod setup;
 pub mod structs;
 pub mod windows;
 pub mod analysis;
 
+#[cfg(feature = "blas")]
 extern crate blas_src;
 
 use anyhow::Result;
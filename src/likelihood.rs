@@ -0,0 +1,93 @@
+use statrs::function::beta::ln_beta;
+
+/// Bounds of the overdispersion searched by [`overdispersion`]
+const OVERDISPERSION: (f64, f64) = (1e-8, 0.5);
+/// Steps of the golden section search for the overdispersion
+const SEARCH_STEPS: usize = 60;
+
+/// Predicted divergences are kept this far from zero and one, so that the log-likelihood stays finite
+const EPSILON: f64 = 1e-12;
+
+/// Number of discordant alleles and of compared alleles of a pair with the `divergence` over `sites` compared sites.
+///
+/// Every site contributes two alleles, which is where the divergence gets its factor of two from.
+/// Bootstrapped divergences need not give whole counts, which the likelihoods below handle just fine,
+/// but they can leave the range of a proportion.
+fn counts(divergence: f64, sites: f64) -> (f64, f64) {
+    (2.0 * sites * divergence.clamp(0.0, 1.0), 2.0 * sites)
+}
+
+/// Negative log-likelihood of the `divergence` of a pair over `sites` compared sites,
+/// if every allele is discordant with the `predicted` probability.
+///
+/// The binomial coefficient doesn't depend on the model and is left out.
+pub fn binomial(divergence: f64, sites: f64, predicted: f64) -> f64 {
+    let (k, n) = counts(divergence, sites);
+    let p = predicted.clamp(EPSILON, 1.0 - EPSILON);
+    -(k * p.ln() + (n - k) * (1.0 - p).ln())
+}
+
+/// Like [`binomial`], but the probability of discordance varies from pair to pair following a beta distribution with the `predicted` mean.
+///
+/// `rho` is the correlation of the alleles of a pair, the beta-binomial approaches the binomial as it goes to zero.
+pub fn beta_binomial(divergence: f64, sites: f64, predicted: f64, rho: f64) -> f64 {
+    let (k, n) = counts(divergence, sites);
+    if predicted.is_nan() {
+        return f64::INFINITY;
+    }
+    let p = predicted.clamp(EPSILON, 1.0 - EPSILON);
+    let a = p * (1.0 / rho - 1.0);
+    let b = (1.0 - p) * (1.0 / rho - 1.0);
+    -(ln_beta(k + a, n - k + b) - ln_beta(a, b))
+}
+
+/// Overdispersion that maximizes the beta-binomial likelihood of the pairs, given as (divergence, sites, predicted).
+///
+/// Golden section search on the log scale, as the overdispersion can be orders of magnitude away from the bounds.
+pub fn overdispersion(pairs: &[(f64, f64, f64)]) -> f64 {
+    let nll = |ln_rho: f64| {
+        pairs
+            .iter()
+            .map(|(d, n, p)| beta_binomial(*d, *n, *p, ln_rho.exp()))
+            .sum::<f64>()
+    };
+    let ratio = (5.0_f64.sqrt() - 1.0) / 2.0;
+    let (mut low, mut high) = (OVERDISPERSION.0.ln(), OVERDISPERSION.1.ln());
+    for _ in 0..SEARCH_STEPS {
+        let left = high - ratio * (high - low);
+        let right = low + ratio * (high - low);
+        if nll(left) <= nll(right) {
+            high = right;
+        } else {
+            low = left;
+        }
+    }
+    ((low + high) / 2.0).exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn beta_binomial_without_overdispersion_is_binomial() {
+        let (d, sites, p) = (0.02, 500.0, 0.025);
+        // Same up to the binomial coefficient, which both leave out
+        let difference = beta_binomial(d, sites, p, 1e-9) - binomial(d, sites, p);
+        assert!(difference.abs() < 1e-3, "difference was {difference}");
+
+        // Binomial data shows no overdispersion
+        let pairs: Vec<(f64, f64, f64)> = [0.0195, 0.0205, 0.0198, 0.0202]
+            .iter()
+            .map(|d| (*d, 10000.0, 0.02))
+            .collect();
+        assert!(overdispersion(&pairs) < 1e-5);
+
+        // Divergences far more spread than the binomial allows do
+        let pairs: Vec<(f64, f64, f64)> = [0.005, 0.04, 0.01, 0.03]
+            .iter()
+            .map(|d| (*d, 10000.0, 0.02))
+            .collect();
+        assert!(overdispersion(&pairs) > 1e-3);
+    }
+}
@@ -0,0 +1,485 @@
+use std::{fmt::Display, fs::File, io::Write, path::Path};
+
+use ndarray::{s, Array2, Axis};
+use rand::{distributions::Distribution, Rng};
+use rayon::prelude::*;
+use statrs::distribution::Normal;
+
+use crate::{
+    analysis::{interval, RawAnalysis, CI},
+    structs::{iteration_rng, Model, Parameterization, Problem, Progress},
+    *,
+};
+
+/// Offset of the random number streams of the chains, see [`iteration_rng`]
+const MCMC_STREAM: u64 = 3 << 32;
+/// Spread of the starting points of the chains around the estimate, in the space of the optimizer
+const START_SPREAD: f64 = 0.5;
+/// Standard deviation of the proposals before there are enough draws to adapt them
+const INITIAL_STEP: f64 = 0.1;
+/// Added to the diagonal of the adapted covariance, so that the proposals never collapse
+const REGULARIZATION: f64 = 1e-8;
+/// Every this many warmup iterations, the proposal takes over the covariance of the draws so far
+const ADAPT_EVERY: usize = 50;
+/// Acceptance rate the warmup tunes the size of the proposals to, optimal for random walks in a few dimensions (Roberts & Rosenthal, 2001)
+const TARGET_ACCEPTANCE: f64 = 0.234;
+/// Chains with a larger split R-hat have not mixed (Vehtari et al., 2021)
+const MAX_RHAT: f64 = 1.01;
+/// Fewer effective draws than this don't give reliable intervals
+const MIN_ESS: f64 = 400.0;
+
+/// Names of the columns of the draws, the layout of [`RawAnalysis`]
+const COLUMNS: [&str; 8] = [
+    "Alpha",
+    "Beta",
+    "Weight",
+    "Intercept",
+    "PrMM",
+    "PrUM",
+    "PrUU",
+    "SelCoef",
+];
+
+/// Prior on the log10 of a rate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Prior {
+    /// Flat between the two bounds
+    Uniform(f64, f64),
+    /// Normal with the mean and standard deviation
+    Normal(f64, f64),
+}
+
+impl Default for Prior {
+    /// Flat over all rates between 1e-10 and 1
+    fn default() -> Self {
+        Prior::Uniform(-10.0, 0.0)
+    }
+}
+
+impl Prior {
+    /// Log density at `x`, the log10 of a rate, up to a constant
+    fn ln_density(&self, x: f64) -> f64 {
+        match self {
+            Prior::Uniform(low, high) if (*low..=*high).contains(&x) => 0.0,
+            Prior::Uniform(_, _) => f64::NEG_INFINITY,
+            Prior::Normal(mean, sd) => -0.5 * ((x - mean) / sd).powi(2),
+        }
+    }
+
+    /// Parse a prior given as `uniform:<low>:<high>` or `normal:<mean>:<sd>`, both on the log10 of the rate
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let numbers: Result<Vec<f64>, _> = parts.iter().skip(1).map(|p| p.parse::<f64>()).collect();
+        match (parts[0], numbers.as_deref()) {
+            ("uniform", Ok([low, high])) if low < high => Ok(Prior::Uniform(*low, *high)),
+            ("normal", Ok([mean, sd])) if *sd > 0.0 => Ok(Prior::Normal(*mean, *sd)),
+            _ => Err(format!(
+                "The prior must be uniform:<low>:<high> or normal:<mean>:<sd> on the log10 of the rate, not {s}"
+            )),
+        }
+    }
+}
+
+impl Display for Prior {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Prior::Uniform(low, high) => write!(f, "uniform:{low}:{high}"),
+            Prior::Normal(mean, sd) => write!(f, "normal:{mean}:{sd}"),
+        }
+    }
+}
+
+/// Priors on the rates. The weight and the selection coefficient are flat on [0, 1], the intercept is flat on the positive numbers
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Priors {
+    pub alpha: Prior,
+    pub beta: Prior,
+}
+
+/// Draws from the posterior of a model, with the diagnostics of the chains
+pub struct Posterior {
+    /// Draws of all chains after the warmup, one chain after the other
+    pub draws: RawAnalysis,
+    pub chains: usize,
+    pub priors: Priors,
+    pub level: f64,
+    /// Share of accepted proposals after the warmup, of every chain
+    pub acceptance: Vec<f64>,
+    /// Split R-hat of every column of the draws
+    pub rhat: Vec<f64>,
+    /// Effective sample size of every column of the draws
+    pub ess: Vec<f64>,
+}
+
+/// Log posterior density of a point in the optimizer's space, up to a constant.
+///
+/// The priors are on the natural parameters, so the density carries the Jacobian of the transformation.
+/// The likelihood leaves out the equilibrium penalty of the cost, the priors on the rates take its place.
+fn log_posterior(problem: &Problem, priors: &Priors, p: &[f64]) -> f64 {
+    let m = problem.to_model(p);
+    let cost = match problem.lack_of_fit_of(&m) {
+        Ok(cost) if cost.is_finite() => cost,
+        _ => return f64::NEG_INFINITY,
+    };
+    let fixed = problem.fixed;
+    // The logistic has the Jacobian p * (1 - p), up to a constant factor for the log10-odds of the rates.
+    // For the rates, the p cancels against the one of the log10 their priors are on
+    let mut density = problem.log_likelihood(cost);
+    if fixed.alpha.is_none() {
+        density += priors.alpha.ln_density(m.alpha.log10()) + (1.0 - m.alpha).ln();
+    }
+    if fixed.beta.is_none() {
+        density += priors.beta.ln_density(m.beta.log10()) + (1.0 - m.beta).ln();
+    }
+    if fixed.weight.is_none() {
+        density += m.weight.ln() + (1.0 - m.weight).ln();
+    }
+    if fixed.intercept.is_none() {
+        density += m.intercept.ln();
+    }
+    if let (Some(sel_coef), None) = (m.sel_coef, fixed.sel_coef) {
+        density += sel_coef.ln() + (1.0 - sel_coef).ln();
+    }
+    if density.is_nan() {
+        return f64::NEG_INFINITY;
+    }
+    density
+}
+
+/// Lower triangular `l` with `l * l^T = a`, for the small covariance matrices of the proposals
+fn cholesky(a: &Array2<f64>) -> Option<Array2<f64>> {
+    let n = a.nrows();
+    let mut l = Array2::<f64>::zeros((n, n));
+    for i in 0..n {
+        for j in 0..=i {
+            let sum = a[[i, j]] - (0..j).map(|k| l[[i, k]] * l[[j, k]]).sum::<f64>();
+            if i == j {
+                if sum <= 0.0 {
+                    return None;
+                }
+                l[[i, j]] = sum.sqrt();
+            } else {
+                l[[i, j]] = sum / l[[j, j]];
+            }
+        }
+    }
+    Some(l)
+}
+
+/// Run one adaptive Metropolis chain (Haario et al., 2001) and return its draws after the warmup and the share of accepted proposals.
+///
+/// During the warmup, which is as long as the sampling, the proposals take over the covariance of the draws so far, scaled by 2.38^2 / d,
+/// and their size is tuned towards the target acceptance rate (Andrieu & Thoms, 2008).
+/// After it, the proposal stays fixed, so that the draws come from a proper Markov chain.
+fn chain(
+    problem: &Problem,
+    priors: &Priors,
+    start: Vec<f64>,
+    draws: usize,
+    rng: &mut impl Rng,
+) -> (Vec<Vec<f64>>, f64) {
+    let d = start.len();
+    let normal = Normal::new(0.0, 1.0).unwrap();
+    let scale = 2.38_f64.powi(2) / d as f64;
+
+    let mut current = start;
+    let mut density = log_posterior(problem, priors, &current);
+    let mut proposal = Array2::<f64>::eye(d) * INITIAL_STEP;
+    let mut ln_step = 0.0_f64;
+
+    // Running mean and sum of squared deviations of the warmup draws (Welford)
+    let mut mean = vec![0.0; d];
+    let mut squares = Array2::<f64>::zeros((d, d));
+
+    let mut kept = Vec::with_capacity(draws);
+    let mut accepted = 0;
+    for i in 0..2 * draws {
+        let z: Vec<f64> = (0..d).map(|_| normal.sample(rng)).collect();
+        let step = ln_step.exp();
+        let candidate: Vec<f64> = (0..d)
+            .map(|j| current[j] + step * (0..=j).map(|k| proposal[[j, k]] * z[k]).sum::<f64>())
+            .collect();
+        let candidate_density = log_posterior(problem, priors, &candidate);
+        let ratio = candidate_density - density;
+        if i < draws && !ratio.is_nan() {
+            ln_step += (ratio.exp().min(1.0) - TARGET_ACCEPTANCE) / ((i + 1) as f64).sqrt();
+        }
+        if rng.gen::<f64>().ln() < ratio {
+            current = candidate;
+            density = candidate_density;
+            if i >= draws {
+                accepted += 1;
+            }
+        }
+
+        if i < draws {
+            let n = (i + 1) as f64;
+            let delta: Vec<f64> = (0..d).map(|j| current[j] - mean[j]).collect();
+            for j in 0..d {
+                mean[j] += delta[j] / n;
+            }
+            for j in 0..d {
+                for k in 0..d {
+                    squares[[j, k]] += delta[j] * (current[k] - mean[k]);
+                }
+            }
+            if (i + 1) % ADAPT_EVERY == 0 && i + 1 >= 2 * d.max(ADAPT_EVERY) {
+                let covariance =
+                    (&squares / (n - 1.0) + Array2::<f64>::eye(d) * REGULARIZATION) * scale;
+                if let Some(l) = cholesky(&covariance) {
+                    proposal = l;
+                }
+            }
+        } else {
+            kept.push(current.clone());
+        }
+    }
+    (kept, accepted as f64 / draws as f64)
+}
+
+/// Sample the posterior of the model of `problem` with `chains` adaptive Metropolis chains of `draws` draws each, after as many warmup iterations.
+///
+/// The chains start around the estimate `best`. The likelihood follows the estimator of the problem, see [`Problem::log_likelihood`].
+/// The credible intervals of the summary have the given `level`.
+pub fn run(
+    problem: &Problem,
+    best: &Model,
+    priors: Priors,
+    chains: usize,
+    draws: usize,
+    level: f64,
+    seed: u64,
+) -> Result<Posterior, Box<dyn std::error::Error>> {
+    if problem.parameterization == Parameterization::Natural {
+        return Err(
+            Error::Simple("The sampler needs the transformed parameters of the model").into(),
+        );
+    }
+    if chains < 2 || draws < 4 {
+        return Err(
+            Error::Simple("The sampler needs at least two chains of four draws each").into(),
+        );
+    }
+    let pb = Progress::new("MCMC", chains).0;
+    let stream = MCMC_STREAM + problem.kind as u64;
+    let center = problem.to_params(best);
+
+    let results: Vec<(Vec<Vec<f64>>, f64)> = (0..chains)
+        .into_par_iter()
+        .map(|c| {
+            let mut rng = iteration_rng(seed, stream, c);
+            let normal = Normal::new(0.0, START_SPREAD).unwrap();
+            let start = center.iter().map(|p| p + normal.sample(&mut rng)).collect();
+            let result = chain(problem, &priors, start, draws, &mut rng);
+            pb.inc(1);
+            result
+        })
+        .collect();
+    pb.finish();
+
+    let columns = if problem.kind.has_selection() { 8 } else { 7 };
+    let mut estimates = Vec::with_capacity(chains * draws * columns);
+    let mut acceptance = Vec::with_capacity(chains);
+    for (kept, accepted) in results {
+        for p in kept {
+            estimates.extend(problem.estimates(&problem.to_model(&p)));
+        }
+        acceptance.push(accepted);
+    }
+    let draws_array = Array2::from_shape_vec((chains * draws, columns), estimates)?;
+
+    let per_chain = |column: usize| -> Vec<Vec<f64>> {
+        (0..chains)
+            .map(|c| {
+                draws_array
+                    .slice(s![c * draws..(c + 1) * draws, column])
+                    .to_vec()
+            })
+            .collect()
+    };
+    let rhat = (0..columns).map(|c| split_rhat(&per_chain(c))).collect();
+    let ess = (0..columns)
+        .map(|c| effective_size(&per_chain(c)))
+        .collect();
+
+    Ok(Posterior {
+        draws: RawAnalysis(draws_array),
+        chains,
+        priors,
+        level,
+        acceptance,
+        rhat,
+        ess,
+    })
+}
+
+/// Mean and variance (with n - 1) of a chain
+fn moments(chain: &[f64]) -> (f64, f64) {
+    let n = chain.len() as f64;
+    let mean = chain.iter().sum::<f64>() / n;
+    let variance = chain.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    (mean, variance)
+}
+
+/// Between- and within-chain variance of equally long chains, and the pooled estimate of the posterior variance
+fn variances(chains: &[&[f64]]) -> (f64, f64) {
+    let n = chains[0].len() as f64;
+    let (means, within): (Vec<f64>, Vec<f64>) = chains.iter().map(|c| moments(c)).unzip();
+    let within = within.iter().sum::<f64>() / within.len() as f64;
+    let between = n * moments(&means).1;
+    (within, (n - 1.0) / n * within + between / n)
+}
+
+/// Split R-hat: The chains are cut in half, and the variance between the halves compared to the variance within them.
+///
+/// Close to one if all chains sample the same distribution.
+pub fn split_rhat(chains: &[Vec<f64>]) -> f64 {
+    let half = chains[0].len() / 2;
+    let halves: Vec<&[f64]> = chains
+        .iter()
+        .flat_map(|c| [&c[..half], &c[half..2 * half]])
+        .collect();
+    let (within, pooled) = variances(&halves);
+    (pooled / within).sqrt()
+}
+
+/// Effective sample size of the draws of all chains, from their autocorrelation (Geyer's initial monotone sequence, as in Stan).
+pub fn effective_size(chains: &[Vec<f64>]) -> f64 {
+    let m = chains.len() as f64;
+    let n = chains[0].len();
+    let slices: Vec<&[f64]> = chains.iter().map(|c| c.as_slice()).collect();
+    let (within, pooled) = variances(&slices);
+    if pooled <= 0.0 {
+        return f64::NAN;
+    }
+    let means: Vec<f64> = chains.iter().map(|c| moments(c).0).collect();
+    // Autocorrelation at lag t, pooled over the chains
+    let rho = |t: usize| {
+        let autocovariance = chains
+            .iter()
+            .zip(&means)
+            .map(|(c, mean)| {
+                (0..n - t)
+                    .map(|i| (c[i] - mean) * (c[i + t] - mean))
+                    .sum::<f64>()
+                    / n as f64
+            })
+            .sum::<f64>()
+            / m;
+        1.0 - (within - autocovariance) / pooled
+    };
+
+    // Sum the autocorrelations in pairs while they are positive and decreasing
+    let mut tau = -1.0;
+    let mut previous = f64::INFINITY;
+    let mut t = 0;
+    while t + 1 < n {
+        let pair = (rho(t) + rho(t + 1)).min(previous);
+        if pair <= 0.0 {
+            break;
+        }
+        tau += 2.0 * pair;
+        previous = pair;
+        t += 2;
+    }
+    m * n as f64 / tau
+}
+
+impl Posterior {
+    /// Print a warning if the chains did not mix or are too short
+    pub fn warn(&self) {
+        for (i, (rhat, ess)) in self.rhat.iter().zip(&self.ess).enumerate() {
+            if *rhat > MAX_RHAT {
+                println!(
+                    "Warning: The chains did not mix for {} (R-hat {rhat}), run longer chains",
+                    COLUMNS[i]
+                );
+            }
+            if *ess < MIN_ESS {
+                println!(
+                    "Warning: Only {ess} effective draws of {}, run longer chains",
+                    COLUMNS[i]
+                );
+            }
+        }
+    }
+
+    pub fn to_file(&self, path: &Path) -> std::io::Result<()> {
+        println!("Writing posterior summary to file: {}", path.display());
+        let mut file = File::create(path)?;
+        file.write_all(self.to_string().as_bytes())
+    }
+}
+
+impl Display for Posterior {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let draws = &self.draws.0;
+        writeln!(f, "Chains\t{}", self.chains)?;
+        writeln!(f, "Draws\t{}", draws.nrows() / self.chains)?;
+        writeln!(f, "PriorAlpha\t{}", self.priors.alpha)?;
+        writeln!(f, "PriorBeta\t{}", self.priors.beta)?;
+        writeln!(f, "CILevel\t{}", self.level)?;
+        let acceptance: Vec<String> = self.acceptance.iter().map(|a| a.to_string()).collect();
+        writeln!(f, "Acceptance\t{}", acceptance.join(","))?;
+
+        writeln!(f, "\nParameter\tMean\tSD\tLower\tUpper\tRhat\tESS")?;
+        for (i, column) in draws.axis_iter(Axis(1)).enumerate() {
+            let CI(lower, upper) = interval(column, self.level, None);
+            writeln!(
+                f,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                COLUMNS[i],
+                column.mean().unwrap(),
+                column.std(1.0),
+                lower,
+                upper,
+                self.rhat[i],
+                self.ess[i]
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn diagnostics_of_independent_chains() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let normal = Normal::new(0.0, 1.0).unwrap();
+        let chains: Vec<Vec<f64>> = (0..4)
+            .map(|_| (0..1000).map(|_| normal.sample(&mut rng)).collect())
+            .collect();
+        assert!((split_rhat(&chains) - 1.0).abs() < 0.01);
+        // Independent draws are all effective
+        let ess = effective_size(&chains);
+        assert!(ess > 3000.0 && ess < 5000.0, "ESS was {ess}");
+
+        // Chains stuck in different places haven't mixed
+        let shifted: Vec<Vec<f64>> = chains
+            .iter()
+            .enumerate()
+            .map(|(i, c)| c.iter().map(|x| x + i as f64).collect())
+            .collect();
+        assert!(split_rhat(&shifted) > 1.5);
+    }
+
+    #[test]
+    fn parses_priors() {
+        assert_eq!(
+            Prior::parse("uniform:-9:-2"),
+            Ok(Prior::Uniform(-9.0, -2.0))
+        );
+        assert_eq!(Prior::parse("normal:-4:1.5"), Ok(Prior::Normal(-4.0, 1.5)));
+        assert!(Prior::parse("uniform:-2:-9").is_err());
+        assert!(Prior::parse("gamma:1:1").is_err());
+        assert_eq!(
+            Prior::parse(&Prior::default().to_string()),
+            Ok(Prior::default())
+        );
+    }
+}
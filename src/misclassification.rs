@@ -0,0 +1,171 @@
+use std::{fmt::Display, fs::File, io::Write, path::Path};
+
+use ndarray::{s, Array1};
+
+use crate::{
+    pedigree::{Genealogy, Pedigree},
+    structs::ErrorModel,
+    *,
+};
+
+/// Divergence expected from miscalled sites of every pair of a pedigree, and how the estimation deals with it.
+///
+/// Every site is called U, I or M with a posterior probability, so one minus that probability is the chance of a miscall.
+/// Miscalls inflate the divergence of a pair, which otherwise ends up in the intercept.
+#[derive(Debug, Clone)]
+pub struct Misclassification {
+    pub model: ErrorModel,
+    /// Names of the samples of every pair, in the order of the pedigree rows
+    pub pairs: Vec<(String, String)>,
+    /// Observed divergence of every pair
+    pub observed: Vec<f64>,
+    /// Divergence of every pair expected from miscalled sites
+    pub expected: Vec<f64>,
+}
+
+impl Misclassification {
+    /// Collect the expected misclassification of every pair of the `pedigree` built along with the `genealogy`
+    pub fn new(pedigree: &Pedigree, genealogy: &Genealogy, model: ErrorModel) -> Self {
+        let name = |node: usize| genealogy.names[node].clone();
+        Misclassification {
+            model,
+            pairs: genealogy
+                .pairs
+                .iter()
+                .map(|(a, b)| (name(*a), name(*b)))
+                .collect(),
+            observed: pedigree.column(3).to_vec(),
+            expected: genealogy.misclassification.clone(),
+        }
+    }
+
+    /// The pedigree to fit the model to under the error model.
+    ///
+    /// Subtracting leaves no divergence below zero. Accounting for the miscalls explicitly adds them as a column
+    /// that [`crate::structs::Problem::divergence`] adds to the prediction of every pair.
+    pub fn apply(&self, pedigree: &Pedigree) -> Result<Pedigree, Error> {
+        if self.expected.len() != pedigree.nrows() {
+            return Err(Error::Simple(
+                "The misclassification does not belong to the pedigree",
+            ));
+        }
+        let expected = Array1::from(self.expected.clone());
+        let mut pedigree = pedigree.clone();
+        match self.model {
+            ErrorModel::None => {}
+            ErrorModel::Subtract => {
+                let corrected = (&pedigree.column(3) - &expected).mapv(|d| d.max(0.0));
+                pedigree.column_mut(3).assign(&corrected);
+            }
+            ErrorModel::Explicit => {
+                if pedigree.sites().is_none() {
+                    return Err(Error::Simple(
+                        "Only pedigrees built from methylomes can account for miscalled sites",
+                    ));
+                }
+                // Only the sites in front of it, in case the pedigree already had a misclassification
+                let mut with_errors = Pedigree::from(pedigree.slice(s![.., ..5]).to_owned());
+                with_errors
+                    .push_column(expected.view())
+                    .expect("Pedigree and misclassification have the same number of pairs");
+                pedigree = with_errors;
+            }
+        }
+        Ok(pedigree)
+    }
+
+    /// Share of the observed divergence, summed over all pairs, that the miscalls are expected to explain.
+    /// `None` if no divergence was observed at all
+    pub fn share(&self) -> Option<f64> {
+        share(self.expected.iter().sum(), self.observed.iter().sum())
+    }
+
+    pub fn to_file(&self, path: &Path) -> std::io::Result<()> {
+        println!("Writing misclassification to file: {}", path.display());
+        let mut file = File::create(path)?;
+        file.write_all(self.to_string().as_bytes())
+    }
+}
+
+impl Display for Misclassification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "ErrorModel\t{}", self.model)?;
+        let na = |share: Option<f64>| share.map_or("NA".to_string(), |s| s.to_string());
+        writeln!(f, "Share\t{}", na(self.share()))?;
+        writeln!(f, "\nSample1\tSample2\tObserved\tMisclassification\tShare")?;
+        for ((a, b), (observed, expected)) in self
+            .pairs
+            .iter()
+            .zip(self.observed.iter().zip(&self.expected))
+        {
+            let share = na(share(*expected, *observed));
+            writeln!(f, "{a}\t{b}\t{observed}\t{expected}\t{share}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Share of the `observed` divergence that the `expected` miscalls explain, `None` without any observed divergence
+fn share(expected: f64, observed: f64) -> Option<f64> {
+    (observed > 0.0).then(|| expected / observed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::structs::{Model, Problem};
+
+    use super::*;
+
+    #[test]
+    fn subtracts_or_adds_the_miscalls() {
+        let (pedigree, _, genealogy) = Pedigree::example();
+        // All calls are at least 99% certain
+        assert_eq!(genealogy.misclassification.len(), pedigree.nrows());
+        assert!(genealogy
+            .misclassification
+            .iter()
+            .all(|e| (0.0..=0.01).contains(e)));
+
+        let apply = |model| {
+            Misclassification::new(&pedigree, &genealogy, model)
+                .apply(&pedigree)
+                .expect("Could not apply the error model")
+        };
+        assert_eq!(*apply(ErrorModel::None), *pedigree);
+
+        let subtracted = apply(ErrorModel::Subtract);
+        for (i, e) in genealogy.misclassification.iter().enumerate() {
+            assert_close!(subtracted[[i, 3]], (pedigree[[i, 3]] - e).max(0.0));
+        }
+
+        // The model predicts the miscalls on top of the epimutations
+        let explicit = apply(ErrorModel::Explicit);
+        let model = Model::default();
+        let prediction = |pedigree: Pedigree| {
+            let problem = Problem {
+                pedigree,
+                ..Problem::default()
+            };
+            problem.divergence(&model).unwrap().dt1t2
+        };
+        let without = prediction(pedigree.clone());
+        let with = prediction(explicit);
+        for ((w, wo), e) in with.iter().zip(without).zip(&genealogy.misclassification) {
+            assert!((w - wo - e).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn no_share_without_divergence() {
+        let misclassification = Misclassification {
+            model: ErrorModel::Subtract,
+            pairs: vec![("a".to_string(), "b".to_string())],
+            observed: vec![0.0],
+            expected: vec![0.001],
+        };
+        assert_eq!(misclassification.share(), None);
+        let output = misclassification.to_string();
+        assert!(output.contains("Share\tNA\n"));
+        assert!(output.ends_with("a\tb\t0\t0.001\tNA\n"));
+    }
+}
@@ -0,0 +1,187 @@
+use argmin::{
+    core::{CostFunction, Executor, Gradient, State, TerminationReason},
+    solver::{
+        linesearch::{condition::ArmijoCondition, BacktrackingLineSearch},
+        neldermead::NelderMead,
+        particleswarm::ParticleSwarm,
+        quasinewton::LBFGS,
+    },
+};
+
+use crate::structs::{Model, Problem};
+
+/// Optimizer used to fit the models, both in the estimation and the bootstrap
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Optimizer {
+    /// Nelder-Mead simplex, derivative free
+    #[default]
+    NelderMead,
+    /// L-BFGS quasi-Newton method, using finite-difference gradients of the cost
+    Lbfgs,
+    /// Particle swarm, a global search within broad bounds on the parameters.
+    /// Draws its own random numbers in every iteration, so runs are not reproducible with a seed
+    ParticleSwarm,
+}
+
+/// Number of particles of the particle swarm
+const PARTICLES: usize = 40;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OptimizerSettings {
+    pub optimizer: Optimizer,
+    /// Maximum number of iterations of a single fit. If not given, every stage of the estimation uses its own default
+    pub max_iters: Option<u64>,
+    /// Tolerance for convergence: Standard deviation of the simplex for Nelder-Mead, change of the cost for L-BFGS.
+    /// The particle swarm always runs for the maximum number of iterations
+    pub tolerance: Option<f64>,
+}
+
+/// Outcome of a single fit
+#[derive(Clone, Debug)]
+pub struct Minimum {
+    /// Best parameters found, in the space of the optimizer (see [`Problem::to_model`])
+    pub param: Vec<f64>,
+    pub iterations: u64,
+    /// Why the optimizer stopped
+    pub termination: String,
+    /// Whether the optimizer stopped because it converged, instead of running out of iterations.
+    /// The particle swarm has no convergence criterion and always counts as converged
+    pub converged: bool,
+}
+
+impl Minimum {
+    fn new<S: State>(state: &S, param: Vec<f64>, always_converged: bool) -> Self {
+        let reason = state.get_termination_reason();
+        Minimum {
+            param,
+            iterations: state.get_iter(),
+            termination: reason.map_or("Not terminated", |r| r.text()).to_string(),
+            converged: always_converged
+                || matches!(
+                    reason,
+                    Some(TerminationReason::SolverConverged | TerminationReason::TargetCostReached)
+                ),
+        }
+    }
+}
+
+impl OptimizerSettings {
+    /// Minimize the cost of `problem` and return the best parameters found, together with how the optimizer got there.
+    ///
+    /// `simplex` holds one starting point more than there are parameters. Nelder-Mead starts from all of them and L-BFGS from the first one.
+    /// The particle swarm ignores them and searches the whole range of valid parameters.
+    pub fn minimize(
+        &self,
+        problem: &Problem,
+        simplex: Vec<Vec<f64>>,
+        default_max_iters: u64,
+    ) -> Result<Minimum, argmin::core::Error> {
+        let max_iters = self.max_iters.unwrap_or(default_max_iters);
+        match self.optimizer {
+            Optimizer::NelderMead => {
+                let mut nm = NelderMead::new(simplex);
+                if let Some(tolerance) = self.tolerance {
+                    nm = nm.with_sd_tolerance(tolerance)?;
+                }
+                let res = Executor::new(problem.clone(), nm)
+                    .configure(|state| state.max_iters(max_iters))
+                    .run()?;
+                let param = res.state.best_param.clone();
+                let param = param.expect("Nelder-Mead returned no parameters");
+                Ok(Minimum::new(&res.state, param, false))
+            }
+            Optimizer::Lbfgs => {
+                // Backtracking steps back from invalid parameters, where the cost is infinite
+                let linesearch = BacktrackingLineSearch::new(ArmijoCondition::new(1e-4)?);
+                let mut lbfgs = LBFGS::new(linesearch, 7);
+                if let Some(tolerance) = self.tolerance {
+                    lbfgs = lbfgs.with_tolerance_cost(tolerance)?;
+                }
+                let start = simplex[0].clone();
+                let res = Executor::new(problem.clone(), lbfgs)
+                    .configure(|state| state.param(start).max_iters(max_iters))
+                    .run()?;
+                let param = res.state.best_param.clone();
+                let param = param.expect("L-BFGS returned no parameters");
+                Ok(Minimum::new(&res.state, param, false))
+            }
+            Optimizer::ParticleSwarm => {
+                let pso = ParticleSwarm::new(search_bounds(problem), PARTICLES);
+                let res = Executor::new(problem.clone(), pso)
+                    .configure(|state| state.max_iters(max_iters))
+                    .run()?;
+                let particle = res.state.best_individual.clone();
+                let param = particle
+                    .expect("Particle swarm returned no particle")
+                    .position;
+                Ok(Minimum::new(&res.state, param, true))
+            }
+        }
+    }
+}
+
+/// Broad bounds on valid parameters, in the space of the optimizer
+fn search_bounds(problem: &Problem) -> (Vec<f64>, Vec<f64>) {
+    let max_divergence = problem
+        .pedigree
+        .column(3)
+        .iter()
+        .fold(f64::MIN_POSITIVE, |a, b| a.max(*b));
+    let sel_coef = |s| problem.kind.has_selection().then_some(s);
+    let lower = Model {
+        alpha: 1e-11,
+        beta: 1e-11,
+        weight: 1e-6,
+        intercept: 1e-9,
+        sel_coef: sel_coef(1e-6),
+        on_boundary: false,
+    };
+    let upper = Model {
+        alpha: 0.1,
+        beta: 0.1,
+        weight: 1.0 - 1e-6,
+        intercept: max_divergence,
+        sel_coef: sel_coef(1.0 - 1e-6),
+        on_boundary: false,
+    };
+    (problem.to_params(&lower), problem.to_params(&upper))
+}
+
+/// Central finite differences of the cost, for the gradient-based optimizers
+impl Gradient for Problem {
+    type Param = Vec<f64>;
+    type Gradient = Vec<f64>;
+
+    fn gradient(&self, p: &Self::Param) -> Result<Self::Gradient, argmin::core::Error> {
+        (0..p.len())
+            .map(|i| {
+                // Relative step, as the natural parameters span many orders of magnitude
+                let h = f64::EPSILON.cbrt() * if p[i] == 0.0 { 1.0 } else { p[i].abs() };
+                let mut forward = p.clone();
+                forward[i] += h;
+                let mut backward = p.clone();
+                backward[i] -= h;
+                Ok((self.cost(&forward)? - self.cost(&backward)?) / (2.0 * h))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finite_differences_point_downhill() {
+        let problem = Problem::default();
+        // Intercept too high
+        let mut p = Model::default().to_vec();
+        p[3] += 0.01;
+        let gradient = problem.gradient(&p).unwrap();
+        assert!(gradient[3] > 0.0);
+
+        let mut step = p.clone();
+        step[3] -= 1e-3 * gradient[3];
+        assert!(problem.cost(&step).unwrap() < problem.cost(&p).unwrap());
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    fmt::Display,
     fs::{self, File},
     io::{BufRead, BufReader, Write},
     ops::{Deref, DerefMut},
@@ -12,13 +13,14 @@ use crate::{
     methylation_site::{MethylationSite, MethylationStatus},
     *,
 };
-use ndarray::{array, Array2, ArrayView, Axis};
+use ndarray::{array, Array2, ArrayView, ArrayView1, Axis};
 #[derive(Clone, Debug)]
 struct Node {
     id: usize,
     file: PathBuf,
     name: String,
-    generation: u32,
+    /// Generation of the sample, or its time in arbitrary units (e.g. years) for somatic pedigrees
+    generation: f64,
     meth: bool,
     proportion_unmethylated: Option<f64>,
     rc_meth_lvl: Option<f64>,
@@ -40,10 +42,67 @@ struct Edge<'a> {
 ///
 /// d: The divergence between the two samples.
 ///
+/// Pedigrees built from methylomes have a fifth column with the number of sites compared between the two samples,
+/// which the likelihood estimators need.
+///
+/// A sixth column can hold the divergence expected from miscalled sites, which the models then add to their prediction,
+/// see [`crate::misclassification`].
+///
 /// The length of the pedigree is the number of possible pairs of samples, for which methlyation data is available => n * (n - 1) / 2
+///
+/// Times don't have to be whole generations: For the somatic model they can be given in any unit, e.g. years.
 #[derive(Clone, Debug)]
 pub struct Pedigree(Array2<f64>);
 
+/// The lineages behind a pedigree built from a nodelist and an edgelist, needed to simulate sites down the real tree.
+#[derive(Clone, Debug)]
+pub struct Genealogy {
+    /// Name of every node of the nodelist
+    pub names: Vec<String>,
+    /// Generation (or time) of every node of the nodelist
+    pub generations: Vec<f64>,
+    /// Parent of every node, the `from` of its edge. `None` for the founders
+    pub parents: Vec<Option<usize>>,
+    /// Nodes of the two samples of every pair, in the order of the pedigree rows
+    pub pairs: Vec<(usize, usize)>,
+    /// Average number of sites compared between the samples of a pair
+    pub sites: usize,
+    /// Proportion of unmethylated sites (one minus the methylation level) of every sample, `None` for unmethylated nodes
+    pub unmethylated: Vec<Option<f64>>,
+    /// Divergence that miscalled sites are expected to add to every pair, in the order of the pedigree rows, see [`crate::misclassification`]
+    pub misclassification: Vec<f64>,
+}
+
+impl Genealogy {
+    /// The nodes that are samples of the pedigree, in the order of the nodelist
+    pub fn samples(&self) -> Vec<usize> {
+        (0..self.names.len())
+            .filter(|node| self.unmethylated[*node].is_some())
+            .collect()
+    }
+
+    /// The samples grouped by lineage: All samples below the same offspring of a founder.
+    /// Founders that are samples form a lineage of their own.
+    pub fn lineages(&self) -> Vec<Vec<usize>> {
+        let mut lineages: Vec<(usize, Vec<usize>)> = Vec::new();
+        for sample in self.samples() {
+            // Walk up to the node right below a founder
+            let mut top = sample;
+            while let Some(parent) = self.parents[top] {
+                if self.parents[parent].is_none() {
+                    break;
+                }
+                top = parent;
+            }
+            match lineages.iter_mut().find(|(t, _)| *t == top) {
+                Some((_, samples)) => samples.push(sample),
+                None => lineages.push((top, vec![sample])),
+            }
+        }
+        lineages.into_iter().map(|(_, samples)| samples).collect()
+    }
+}
+
 impl Pedigree {
     /// Read a pedigree from a file.
     ///
@@ -57,22 +116,26 @@ impl Pedigree {
     ///
     ///` d`: The divergence between the two samples.
     ///
+    /// An optional fifth column holds the number of compared sites, and an optional sixth the expected divergence from miscalled sites.
+    ///
     /// The first line of the file is ignored.
     /// I chose not to return a result, as this function is meant to statically read a file and therefore it is preferable to panic if the file is not found or parsing errors occur.
     pub fn from_file(filename: &str) -> Self {
-        let mut pedigree = Array2::<f64>::zeros((0, 4));
         let file = std::fs::read_to_string(filename).unwrap();
+        let columns = file
+            .split('\n')
+            .nth(1)
+            .map_or(4, |line| line.split(' ').count().clamp(4, 6));
+        let mut pedigree = Array2::<f64>::zeros((0, columns));
         file.split('\n').skip(1).for_each(|line| {
             if line.is_empty() {
                 return;
             }
-            let mut entries = line.split(' ');
-            let row = [
-                entries.next().unwrap().parse::<f64>().unwrap(),
-                entries.next().unwrap().parse::<f64>().unwrap(),
-                entries.next().unwrap().parse::<f64>().unwrap(),
-                entries.next().unwrap().parse::<f64>().unwrap(),
-            ];
+            let row: Vec<f64> = line
+                .split(' ')
+                .take(columns)
+                .map(|entry| entry.parse::<f64>().unwrap())
+                .collect();
             pedigree.push_row(ArrayView::from(&row)).unwrap();
         });
         Pedigree(pedigree)
@@ -82,18 +145,63 @@ impl Pedigree {
         println!("Writing pedigree to file: {}", path.display());
         let mut file = File::create(path)?;
         let mut content = String::new();
-        content += "time0\ttime1\ttime2\tD.value\n";
+        content += "time0\ttime1\ttime2\tD.value";
+        if self.sites().is_some() {
+            content += "\tSites";
+        }
+        if self.misclassification().is_some() {
+            content += "\tMisclassification";
+        }
+        content.push('\n');
         for row in self.rows() {
-            content.push_str(&format!("{}\t{}\t{}\t{}\n", row[0], row[1], row[2], row[3]));
+            content.push_str(&format!("{}\t{}\t{}\t{}", row[0], row[1], row[2], row[3]));
+            for extra in row.iter().skip(4) {
+                content.push_str(&format!("\t{extra}"));
+            }
+            content.push('\n');
         }
         file.write_all(content.as_bytes())
     }
 
+    /// Time that separates the samples of every pair: t1 + t2 - 2 * t0
+    pub fn delta_t(&self) -> Vec<f64> {
+        self.rows()
+            .into_iter()
+            .map(|row| row[1] + row[2] - 2.0 * row[0])
+            .collect()
+    }
+
+    /// Number of sites compared between the samples of every pair, if the pedigree has them
+    pub fn sites(&self) -> Option<ArrayView1<'_, f64>> {
+        (self.ncols() > 4).then(|| self.column(4))
+    }
+
+    /// Divergence that miscalled sites are expected to add to every pair, if the model is to account for it
+    pub fn misclassification(&self) -> Option<ArrayView1<'_, f64>> {
+        (self.ncols() > 5).then(|| self.column(5))
+    }
+
+    /// Build the pedigree of all pairs of methylated samples of a nodelist and an edgelist, with their divergence measured according to `mode`.
+    ///
+    /// Also returns the proportion of unmethylated sites, one minus the average methylation level of the samples at the sites called with at least `posterior_max_filter`.
     pub fn build(
         nodelist: &Path,
         edgelist: &Path,
         posterior_max_filter: f64,
+        mode: DivergenceMode,
     ) -> Result<(Self, f64), Error> {
+        let (pedigree, p0uu, _) =
+            Pedigree::build_with_genealogy(nodelist, edgelist, posterior_max_filter, mode)?;
+        Ok((pedigree, p0uu))
+    }
+
+    /// Same as [`Pedigree::build`], but also returns the genealogy of the samples
+    pub fn build_with_genealogy(
+        nodelist: &Path,
+        edgelist: &Path,
+        posterior_max_filter: f64,
+        mode: DivergenceMode,
+    ) -> Result<(Self, f64, Genealogy), Error> {
         let nodes = fs::read_to_string(nodelist)?;
         let edges = fs::read_to_string(edgelist)?;
         let nodes: Vec<Node> = nodes
@@ -106,7 +214,7 @@ impl Pedigree {
                     id: i,
                     file: PathBuf::from(entries.next()?),
                     name: String::from(entries.next()?),
-                    generation: entries.next()?.parse::<u32>().ok()?,
+                    generation: entries.next()?.parse::<f64>().ok()?,
                     meth: entries.next()? == "Y",
                     proportion_unmethylated: None,
                     rc_meth_lvl: None,
@@ -120,6 +228,7 @@ impl Pedigree {
             ));
         }
 
+        let mut parents = vec![None; nodes.len()];
         let edges: Vec<Edge> = edges
             .split(['\n', '\r'])
             .skip(1)
@@ -133,6 +242,12 @@ impl Pedigree {
                 })
             })
             .collect();
+        for edge in &edges {
+            parents[edge.to.id] = Some(edge.from.id);
+        }
+        let generations = nodes.iter().map(|n| n.generation).collect();
+        let names = nodes.iter().map(|n| n.name.clone()).collect();
+        let mut unmethylated = vec![None; nodes.len()];
 
         let mut nodes: Vec<Node> = nodes.iter().filter(|n| n.meth).cloned().collect();
         for node in nodes.iter_mut() {
@@ -176,6 +291,9 @@ impl Pedigree {
             node.sites = Some(sites);
         }
 
+        for node in &nodes {
+            unmethylated[node.id] = node.rc_meth_lvl.map(|l| 1.0 - l);
+        }
         let tmp0uu_meth_lvl = nodes
             .iter()
             .map(|n| 1.0 - n.rc_meth_lvl.unwrap())
@@ -187,9 +305,37 @@ impl Pedigree {
         //     (tmp0uu - tmp0uu_meth_lvl).abs() / tmp0uu
         // );
 
-        let divergence = DMatrix::from(&nodes, posterior_max_filter);
-        let pedigree = divergence.convert(&nodes, &edges);
-        Ok((pedigree, tmp0uu_meth_lvl))
+        let divergence = DMatrix::from(&nodes, posterior_max_filter, mode);
+        let (pedigree, pairs) = divergence.convert(&nodes, &edges);
+        // Like the divergences, the compared sites are indexed by the first sample and the offset of the second one
+        let compared_sites = pairs
+            .iter()
+            .map(|(i, j)| divergence.1[[*i, j - i - 1]])
+            .sum::<f64>();
+        let genealogy = Genealogy {
+            names,
+            generations,
+            parents,
+            pairs: pairs
+                .iter()
+                .map(|(i, j)| (nodes[*i].id, nodes[*j].id))
+                .collect(),
+            sites: (compared_sites / pairs.len().max(1) as f64).round() as usize,
+            unmethylated,
+            misclassification: pairs
+                .iter()
+                .map(|(i, j)| divergence.2[[*i, j - i - 1]])
+                .collect(),
+        };
+        Ok((pedigree, tmp0uu_meth_lvl, genealogy))
+    }
+}
+
+impl From<Array2<f64>> for Pedigree {
+    /// Use an existing matrix with the four columns t0, t1, t2 and d, and optionally the compared sites and the misclassification, as pedigree
+    fn from(pedigree: Array2<f64>) -> Self {
+        assert!((4..=6).contains(&pedigree.ncols()));
+        Pedigree(pedigree)
     }
 }
 
@@ -207,12 +353,48 @@ impl DerefMut for Pedigree {
     }
 }
 
+/// How the divergence between two samples is measured from their methylation sites
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DivergenceMode {
+    /// Differing alleles of the called status, at the sites both samples called with at least the posterior filter
+    #[default]
+    Hard,
+    /// Differing alleles of the called status at all sites, each weighted by the joint posterior of both calls.
+    /// Borderline sites count, but less than certain ones
+    Soft,
+    /// Difference of the methylation levels (rc.meth.lvl) at all sites, without calling a status.
+    /// On the same scale as the alleles, as UU, UM and MM have the levels 0, 0.5 and 1
+    MethLevel,
+}
+
+impl Display for DivergenceMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Hard => "Hard",
+            Self::Soft => "Soft",
+            Self::MethLevel => "MethLevel",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Divergence, number of compared sites and expected divergence from miscalled sites of every pair of samples.
+///
+/// With the soft divergence, the number of compared sites is the sum of the weights of the sites.
 #[derive(Debug)]
-struct DMatrix(Array2<f64>);
+struct DMatrix(Array2<f64>, Array2<f64>, Array2<f64>);
 
 impl DMatrix {
-    fn from(nodes: &Vec<Node>, posterior_max: f64) -> Self {
+    /// Compare the sites of every pair of samples according to `mode`.
+    ///
+    /// A call is wrong with one minus its posterior probability. A miscalled site is assumed to be off by one allele
+    /// (e.g. I called as U) at a site where the samples truly agree, so it adds one discordant allele of two.
+    /// As most sites agree, that is the divergence the miscalls are expected to add.
+    /// The methylation levels involve no calls, so they have no miscalls.
+    fn from(nodes: &Vec<Node>, posterior_max: f64, mode: DivergenceMode) -> Self {
         let mut divergences = Array2::<f64>::zeros((nodes.len(), nodes.len()));
+        let mut compared = Array2::<f64>::zeros((nodes.len(), nodes.len()));
+        let mut misclassification = Array2::<f64>::zeros((nodes.len(), nodes.len()));
 
         // Go over all pairs of nodes, excluding self-pairs
         for (i, first) in nodes.iter().enumerate() {
@@ -233,8 +415,10 @@ impl DMatrix {
                     second.sites.as_ref().unwrap().len()
                 );
 
-                let mut divergence = 0;
-                let mut compared_sites = 0;
+                // Differing alleles and compared sites, both weighted
+                let mut divergence = 0.0;
+                let mut compared_sites = 0.0;
+                let mut miscalls = 0.0;
 
                 // Go over all sites in the first sample
                 // IMPORTANT: It is assumed that the same sites are included in the datasets and that the sites are sorted by position
@@ -246,37 +430,50 @@ impl DMatrix {
                         .get(k)
                         .expect("Partner methylation site must exists");
 
-                    if f.posteriormax < posterior_max || s.posteriormax < posterior_max {
-                        continue;
+                    let alleles = f.status_numeric().abs_diff(s.status_numeric()) as f64;
+                    let (weight, differing) = match mode {
+                        DivergenceMode::Hard => {
+                            if f.posteriormax < posterior_max || s.posteriormax < posterior_max {
+                                continue;
+                            }
+                            (1.0, alleles)
+                        }
+                        DivergenceMode::Soft => (f.posteriormax * s.posteriormax, alleles),
+                        DivergenceMode::MethLevel => (1.0, 2.0 * (f.meth_lvl - s.meth_lvl).abs()),
+                    };
+
+                    divergence += weight * differing;
+                    compared_sites += weight;
+                    if mode != DivergenceMode::MethLevel {
+                        miscalls += weight * ((1.0 - f.posteriormax) + (1.0 - s.posteriormax));
                     }
-
-                    divergence += f.status_numeric().abs_diff(s.status_numeric());
-                    compared_sites += 1;
                 }
 
-                let divergence = divergence as f64 / (2.0 * compared_sites as f64);
-                divergences[[i, j]] = divergence;
+                divergences[[i, j]] = divergence / (2.0 * compared_sites);
+                compared[[i, j]] = compared_sites;
+                misclassification[[i, j]] = miscalls / (2.0 * compared_sites);
             }
         }
-        DMatrix(divergences)
+        DMatrix(divergences, compared, misclassification)
     }
-    /// Convert graph of divergences to pedigree
-    fn convert(&self, nodes: &[Node], edges: &[Edge]) -> Pedigree {
+    /// Convert graph of divergences to pedigree, together with the indices of the two samples of every row
+    fn convert(&self, nodes: &[Node], edges: &[Edge]) -> (Pedigree, Vec<(usize, usize)>) {
         let e = edges
             .iter()
             .map(|e| {
                 (
                     e.from.id,
                     e.to.id,
-                    e.from.generation.abs_diff(e.to.generation) as usize,
+                    (e.from.generation - e.to.generation).abs(),
                     // self.0.get((e.from.id, e.to.id)).unwrap(),
                 )
             })
-            .collect::<Vec<(usize, usize, usize)>>();
+            .collect::<Vec<(usize, usize, f64)>>();
 
-        let graph = UnGraph::<usize, usize, usize>::from_edges(e);
+        let graph = UnGraph::<usize, f64, usize>::from_edges(e);
 
-        let mut pedigree = Pedigree(Array2::<f64>::default((0, 4)));
+        let mut pedigree = Pedigree(Array2::<f64>::default((0, 5)));
+        let mut pairs = Vec::new();
 
         for (i, source) in nodes.iter().enumerate() {
             for (j, target) in nodes.iter().skip(i + 1).enumerate() {
@@ -290,7 +487,7 @@ impl DMatrix {
                     source.id.into(),
                     |finish| finish == target.id.into(),
                     |e| *e.weight(),
-                    |_| 0,
+                    |_| 0.0,
                 );
 
                 match path {
@@ -315,25 +512,41 @@ impl DMatrix {
                                     })
                                     .unwrap()
                             })
-                            .min()
-                            .unwrap() as f64;
+                            .fold(f64::INFINITY, f64::min);
 
-                        let t1 = source.generation as f64;
-                        let t2 = target.generation as f64;
+                        let t1 = source.generation;
+                        let t2 = target.generation;
 
                         let div = self.0.get((i, j)).unwrap().to_owned();
+                        let sites = self.1[[i, j]];
 
-                        assert_eq!(distance as f64, t1 - t0 + t2 - t0);
+                        assert!((distance - (t1 - t0 + t2 - t0)).abs() < 1e-9);
                         pedigree
                             .0
-                            .push(Axis(0), array![t0, t1, t2, div].view())
+                            .push(Axis(0), array![t0, t1, t2, div, sites].view())
                             .expect("Could not insert row into pedigree");
+                        pairs.push((i, i + 1 + j));
                     }
                 }
             }
         }
 
-        pedigree
+        (pedigree, pairs)
+    }
+}
+
+/// The example data of the tests
+#[cfg(test)]
+impl Pedigree {
+    /// Pedigree and genealogy of the example nodelist and edgelist, with the sites called with at least 99% certainty
+    pub(crate) fn example() -> (Pedigree, f64, Genealogy) {
+        Pedigree::build_with_genealogy(
+            Path::new("./data/nodelist.txt"),
+            Path::new("./data/edgelist.txt"),
+            0.99,
+            DivergenceMode::Hard,
+        )
+        .expect("Could not build pedigree")
     }
 }
 
@@ -346,9 +559,11 @@ mod tests {
         let nodelist = Path::new("./data/nodelist.txt");
         let edgelist = Path::new("./data/edgelist.txt");
 
-        let pedigree = Pedigree::build(nodelist, edgelist, 0.99).expect("Could not build pedigree");
+        let pedigree = Pedigree::build(nodelist, edgelist, 0.99, DivergenceMode::Hard)
+            .expect("Could not build pedigree");
 
-        assert_eq!(pedigree.0.shape(), &[4 * 3 / 2, 4]);
+        assert_eq!(pedigree.0.shape(), &[4 * 3 / 2, 5]);
+        assert!(pedigree.0.sites().unwrap().iter().all(|s| *s > 0.0));
         pedigree
             .0
             .to_file(Path::new("./data/pedigree_generated.txt"))
@@ -357,6 +572,47 @@ mod tests {
         // assert_close!(pedigree.1, 0.4567024);
     }
 
+    #[test]
+    fn soft_and_level_divergence_keep_borderline_sites() {
+        let build = |filter, mode| {
+            let (pedigree, _) = Pedigree::build(
+                Path::new("./data/nodelist.txt"),
+                Path::new("./data/edgelist.txt"),
+                filter,
+                mode,
+            )
+            .expect("Could not build pedigree");
+            pedigree
+        };
+        let hard = build(0.99, DivergenceMode::Hard);
+        let all = build(0.0, DivergenceMode::Hard);
+        let soft = build(0.99, DivergenceMode::Soft);
+        let level = build(0.99, DivergenceMode::MethLevel);
+
+        for i in 0..hard.nrows() {
+            // Borderline sites count, but less than all sites would
+            assert!(hard[[i, 4]] < soft[[i, 4]] && soft[[i, 4]] < all[[i, 4]]);
+            assert_eq!(level[[i, 4]], all[[i, 4]]);
+            for pedigree in [&soft, &level] {
+                assert!((0.0..=1.0).contains(&pedigree[[i, 3]]));
+            }
+        }
+    }
+
+    #[test]
+    fn groups_samples_by_lineage() {
+        let (_, _, genealogy) = Pedigree::example();
+
+        let names = |nodes: &Vec<usize>| {
+            nodes
+                .iter()
+                .map(|n| genealogy.names[*n].as_str())
+                .collect::<Vec<_>>()
+        };
+        let lineages: Vec<Vec<&str>> = genealogy.lineages().iter().map(names).collect();
+        assert_eq!(lineages, vec![vec!["0_0"], vec!["1_2", "4_2"], vec!["4_8"]]);
+    }
+
     // #[test]
     // fn wildtype_pedigree() {
     //     let nodelist = Path::new("./data/nodelist.txt");
@@ -365,7 +621,7 @@ mod tests {
     //         "./data/desired_output/pedigree-pdata_epimutation_rate_estimation_window_gene_0.txt",
     //     );
 
-    //     let pedigree = Pedigree::build(nodelist, edgelist, 0.99).expect("Could not build pedigree");
+    //     let pedigree = Pedigree::build(nodelist, edgelist, 0.99, DivergenceMode::Hard).expect("Could not build pedigree");
 
     //     // TODO: Enable
     //     // assert_close!(pedigree.1, 0.991008120326199);
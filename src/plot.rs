@@ -1,4 +1,4 @@
-use crate::{arguments::Windows, *, analysis::Analysis};
+use crate::{arguments::Windows, *, analysis::Analysis, goodness_of_fit::GoodnessOfFit};
 use itertools::Itertools;
 use plotters::prelude::*;
 use std::path::Path;
@@ -136,6 +136,98 @@ pub fn bootstrap(alphas: Vec<f64>, betas: Vec<f64>, output_dir: &Path) -> Result
     Ok(())
 }
 
+pub fn fit(gof: &GoodnessOfFit, output_dir: &Path) -> Result<()> {
+    let output_file = output_dir.join("fit.png");
+
+    let max_t = gof.curve.iter().map(|p| p.0).fold(0.0f64, |a, b| a.max(b));
+    let max_d = gof
+        .pairs
+        .iter()
+        .map(|p| p.1)
+        .chain(gof.curve.iter().map(|p| p.1))
+        .fold(0.0f64, |a, b| a.max(b));
+    let max_r = gof
+        .pairs
+        .iter()
+        .map(|p| p.3.abs())
+        .fold(0.0f64, |a, b| a.max(b));
+
+    let root = BitMapBackend::new(&output_file, (640 * 2, 480 * 2)).into_drawing_area();
+
+    root.fill(&WHITE)?;
+    let (upper, lower) = root.split_vertically(480 * 2 * 2 / 3);
+
+    let mut chart = ChartBuilder::on(&upper)
+        .caption(
+            format!("Divergence over time (R² = {:.3})", gof.r_squared),
+            ("sans-serif", 40).into_font(),
+        )
+        .margin(20)
+        .x_label_area_size(30)
+        .y_label_area_size(90)
+        .build_cartesian_2d(0f32..(max_t * 1.05) as f32, 0f32..(max_d * 1.1) as f32)?;
+
+    chart
+        .configure_mesh()
+        .y_desc("Divergence")
+        .axis_desc_style(("sans-serif", 30))
+        .draw()?;
+
+    chart
+        .draw_series(
+            gof.pairs
+                .iter()
+                .map(|p| Circle::new((p.0 as f32, p.1 as f32), 5, BLACK.filled())),
+        )?
+        .label("Observed")
+        .legend(|(x, y)| Circle::new((x + 10, y), 5, BLACK.filled()));
+
+    chart
+        .draw_series(LineSeries::new(
+            gof.curve.iter().map(|p| (p.0 as f32, p.1 as f32)),
+            &RED,
+        ))?
+        .label("Theoretical")
+        .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], RED));
+
+    chart
+        .configure_series_labels()
+        .position(SeriesLabelPosition::LowerRight)
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()?;
+
+    let mut residuals = ChartBuilder::on(&lower)
+        .margin(20)
+        .x_label_area_size(60)
+        .y_label_area_size(90)
+        .build_cartesian_2d(
+            0f32..(max_t * 1.05) as f32,
+            -(max_r * 1.2) as f32..(max_r * 1.2) as f32,
+        )?;
+
+    residuals
+        .configure_mesh()
+        .x_desc("Delta t")
+        .y_desc("Residual")
+        .axis_desc_style(("sans-serif", 30))
+        .draw()?;
+
+    residuals.draw_series(LineSeries::new(
+        vec![(0f32, 0f32), ((max_t * 1.05) as f32, 0f32)],
+        &BLUE,
+    ))?;
+    residuals.draw_series(
+        gof.pairs
+            .iter()
+            .map(|p| Circle::new((p.0 as f32, p.3 as f32), 5, BLACK.filled())),
+    )?;
+
+    root.present()?;
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     // use std::path::PathBuf;
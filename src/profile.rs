@@ -0,0 +1,230 @@
+use std::{fmt::Display, fs::File, io::Write, path::Path};
+
+use rayon::prelude::*;
+use statrs::distribution::{ChiSquared, ContinuousCDF};
+
+use crate::{
+    analysis::CI,
+    optimizer::OptimizerSettings,
+    structs::{iteration_rng, Fixed, Model, Problem},
+    *,
+};
+
+/// Offset of the random number streams of the profiles, see [`iteration_rng`]
+const PROFILE_STREAM: u64 = 2 << 32;
+/// Number of points of a profile
+const POINTS: usize = 41;
+/// The profile spans this factor below and above the estimate
+const SPAN: f64 = 100.0;
+
+/// The parameters that can be profiled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Profiled {
+    Alpha,
+    Beta,
+}
+
+impl Profiled {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Profiled::Alpha => "Alpha",
+            Profiled::Beta => "Beta",
+        }
+    }
+
+    fn get(&self, m: &Model) -> f64 {
+        match self {
+            Profiled::Alpha => m.alpha,
+            Profiled::Beta => m.beta,
+        }
+    }
+
+    pub fn is_fixed(&self, fixed: &Fixed) -> bool {
+        match self {
+            Profiled::Alpha => fixed.alpha.is_some(),
+            Profiled::Beta => fixed.beta.is_some(),
+        }
+    }
+
+    fn fix(&self, fixed: &mut Fixed, value: f64) {
+        match self {
+            Profiled::Alpha => fixed.alpha = Some(value),
+            Profiled::Beta => fixed.beta = Some(value),
+        }
+    }
+}
+
+/// Profile of the lack of fit over one parameter, with the other parameters re-optimized at every point
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub parameter: Profiled,
+    pub estimate: f64,
+    pub level: f64,
+    pub ci: CI,
+    /// Whether the interval reaches the end of the profile on either side, so that the true bound lies further out
+    pub open: (bool, bool),
+    /// Value of the parameter, lack of fit and likelihood ratio statistic of every point, in ascending order of the parameter
+    pub points: Vec<(f64, f64, f64)>,
+}
+
+/// Profile `parameter` of the model `best` fitted to `problem` and derive a confidence interval of the given `level`.
+///
+/// The likelihood ratio statistic is twice the drop of the log-likelihood from the estimate, see [`Problem::log_likelihood`].
+/// For least squares, that is n * ln(lack of fit / minimal lack of fit).
+/// The interval contains the values where it stays below the chi-squared quantile with one degree of freedom.
+/// The equilibrium penalty is no likelihood, so the profile leaves it out, both when refitting and in the statistic.
+pub fn run(
+    problem: &Problem,
+    best: &Model,
+    parameter: Profiled,
+    level: f64,
+    seed: u64,
+    optimizer: OptimizerSettings,
+) -> Result<Profile, Box<dyn std::error::Error>> {
+    if parameter.is_fixed(&problem.fixed) {
+        return Err(Error::Simple("A fixed parameter has no profile").into());
+    }
+    let estimate = parameter.get(best);
+    let problem = &Problem {
+        eqp_weight: 0.0,
+        ..problem.clone()
+    };
+    let best_likelihood = problem.log_likelihood(problem.lack_of_fit_of(best)?);
+
+    // Evenly spaced on the log scale, as the rates span orders of magnitude
+    let (low, high) = (estimate / SPAN, (estimate * SPAN).min(1.0 - 1e-6));
+    let grid: Vec<f64> = (0..POINTS)
+        .map(|i| low * (high / low).powf(i as f64 / (POINTS - 1) as f64))
+        .collect();
+
+    let stream = PROFILE_STREAM + parameter as u64;
+    let costs = grid
+        .par_iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let mut rng = iteration_rng(seed, stream, i);
+            let mut problem = problem.clone();
+            parameter.fix(&mut problem.fixed, *value);
+
+            // Start from the best model, supplemented with random values close-by
+            let mut simplex = vec![problem.to_params(best)];
+            simplex.extend(
+                (0..problem.n_params()).map(|_| problem.to_params(&problem.vary(best, &mut rng))),
+            );
+            let minimum = optimizer.minimize(&problem, simplex, 1000)?;
+            Ok(problem.lack_of_fit_of(&problem.to_model(&minimum.param))?)
+        })
+        .collect::<Result<Vec<f64>, argmin::core::Error>>()?;
+
+    // Without the penalty, the best fit may lie a little off the penalized estimate, on the profile
+    let max_likelihood = costs
+        .iter()
+        .map(|cost| problem.log_likelihood(*cost))
+        .fold(best_likelihood, f64::max);
+
+    let threshold = ChiSquared::new(1.0)?.inverse_cdf(level);
+    let points: Vec<(f64, f64, f64)> = grid
+        .into_iter()
+        .zip(costs)
+        .map(|(value, cost)| {
+            let statistic = 2.0 * (max_likelihood - problem.log_likelihood(cost));
+            (value, cost, statistic.max(0.0))
+        })
+        .collect();
+
+    // Walk outwards from the estimate until the statistic crosses the threshold, and interpolate the crossing
+    let center = points.partition_point(|p| p.0 < estimate);
+    let crossing = |inside: &(f64, f64, f64), outside: &(f64, f64, f64)| {
+        let share = (threshold - inside.2) / (outside.2 - inside.2);
+        inside.0 + share * (outside.0 - inside.0)
+    };
+    let lower = (1..center.min(points.len()))
+        .rev()
+        .find(|i| points[i - 1].2 > threshold && points[*i].2 <= threshold)
+        .map(|i| crossing(&points[i], &points[i - 1]));
+    let upper = (center..points.len() - 1)
+        .find(|i| points[*i].2 <= threshold && points[i + 1].2 > threshold)
+        .map(|i| crossing(&points[i], &points[i + 1]));
+
+    let profile = Profile {
+        parameter,
+        estimate,
+        level,
+        ci: CI(lower.unwrap_or(low), upper.unwrap_or(high)),
+        open: (lower.is_none(), upper.is_none()),
+        points,
+    };
+    if profile.open.0 || profile.open.1 {
+        println!(
+            "Warning: The profile interval of {} reaches the end of the profile, the true interval is wider",
+            parameter.name()
+        );
+    }
+    Ok(profile)
+}
+
+impl Profile {
+    pub fn to_file(&self, path: &Path) -> std::io::Result<()> {
+        println!("Writing profile to file: {}", path.display());
+        let mut file = File::create(path)?;
+        file.write_all(self.to_string().as_bytes())
+    }
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self.parameter.name();
+        writeln!(f, "Estimate\t{}", self.estimate)?;
+        writeln!(f, "CILevel\t{}", self.level)?;
+        writeln!(f, "CI{name}\t{}-{}", self.ci.0, self.ci.1)?;
+        writeln!(f, "OpenLower\t{}\nOpenUpper\t{}", self.open.0, self.open.1)?;
+        writeln!(f, "\n{name}\tLackOfFit\tStatistic")?;
+        for (value, cost, statistic) in &self.points {
+            writeln!(f, "{value}\t{cost}\t{statistic}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ab_neutral,
+        pedigree::Pedigree,
+        structs::{Estimator, MatingSystem, ModelKind},
+    };
+
+    use super::*;
+
+    #[test]
+    fn interval_contains_estimate() {
+        let (pedigree, p0uu, _) = Pedigree::example();
+        let problem = Problem::new(
+            pedigree,
+            p0uu,
+            p0uu,
+            1.0,
+            ModelKind::Neutral,
+            MatingSystem::Selfing,
+            Estimator::LeastSquares,
+        );
+        let (best, _, _, _) =
+            ab_neutral::estimate(&problem, 2, 0, OptimizerSettings::default(), None)
+                .expect("Model failed");
+
+        let profile = run(
+            &problem,
+            &best,
+            Profiled::Alpha,
+            0.95,
+            0,
+            OptimizerSettings::default(),
+        )
+        .expect("Profile failed");
+
+        assert_eq!(profile.points.len(), POINTS);
+        assert!(profile.ci.0 <= best.alpha && best.alpha <= profile.ci.1);
+        // Next to the estimate, fixing the rate costs next to nothing
+        assert!(profile.points.iter().any(|p| p.2 < 0.1));
+    }
+}
@@ -1,12 +1,20 @@
-use std::{fmt::Display, fs::File, io::Write, ops::Deref, path::Path};
+use std::{f64::consts::LN_10, fmt::Display, fs::File, io::Write, ops::Deref, path::Path};
 
 use argmin::core::CostFunction;
 
 use indicatif::{ProgressBar, ProgressStyle};
-use ndarray::Array1;
-use rand::{distributions::Uniform, thread_rng, Rng};
-
-use crate::{divergence::divergence, pedigree::Pedigree, *};
+use ndarray::{Array1, Array2};
+use rand::{distributions::Uniform, rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    divergence::{
+        divergence, divergence_soma, divergence_with_genmatrix, founder_state, genmatrix,
+        genmatrix_outcross, genmatrix_soma, matrix_power, select, soma_matrix_power,
+        steady_state_distribution, Divergence,
+    },
+    pedigree::Pedigree,
+    *,
+};
 
 #[derive(Clone, Debug)]
 pub struct Problem {
@@ -16,13 +24,285 @@ pub struct Problem {
     pub p_mm: f64,
     pub p_um: f64,
     pub p_uu: f64,
+    pub kind: ModelKind,
+    pub mating: MatingSystem,
+    pub parameterization: Parameterization,
+    /// Parameters held at a given value instead of being estimated
+    pub fixed: Fixed,
+    pub estimator: Estimator,
+}
+
+/// Parameters of a model that are not estimated, in natural units. The optimizer only sees the others
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Fixed {
+    pub alpha: Option<f64>,
+    pub beta: Option<f64>,
+    pub weight: Option<f64>,
+    pub intercept: Option<f64>,
+    pub sel_coef: Option<f64>,
+}
+
+impl Fixed {
+    /// In the order of [`Model::to_vec`]
+    pub fn to_vec(&self) -> [Option<f64>; 5] {
+        [
+            self.alpha,
+            self.beta,
+            self.weight,
+            self.intercept,
+            self.sel_coef,
+        ]
+    }
+
+    /// Number of fixed parameters, of the first `n_params`
+    pub fn count(&self, n_params: usize) -> usize {
+        self.to_vec()[..n_params].iter().flatten().count()
+    }
+
+    /// Set the fixed parameters of a model
+    pub fn apply(&self, m: &mut Model) {
+        m.alpha = self.alpha.unwrap_or(m.alpha);
+        m.beta = self.beta.unwrap_or(m.beta);
+        m.weight = self.weight.unwrap_or(m.weight);
+        m.intercept = self.intercept.unwrap_or(m.intercept);
+        if m.sel_coef.is_some() {
+            m.sel_coef = self.sel_coef.or(m.sel_coef);
+        }
+    }
+
+    /// Check that the fixed values are valid for the given kind of model and leave something to estimate
+    pub fn validate(&self, kind: ModelKind) -> Result<(), Error> {
+        if self.sel_coef.is_some() && !kind.has_selection() {
+            return Err(Error::Simple(
+                "Only the selection models have a selection coefficient to fix",
+            ));
+        }
+        if self.count(kind.n_params()) == kind.n_params() {
+            return Err(Error::Simple(
+                "All parameters of the model are fixed, at least one has to be estimated",
+            ));
+        }
+        let rates = [self.alpha, self.beta, self.sel_coef];
+        if rates.iter().flatten().any(|r| *r <= 0.0 || *r >= 1.0) {
+            return Err(Error::Simple(
+                "Fixed rates and selection coefficients must be between 0 and 1",
+            ));
+        }
+        if [self.weight, self.intercept]
+            .iter()
+            .flatten()
+            .any(|p| !(0.0..=1.0).contains(p))
+        {
+            return Err(Error::Simple(
+                "A fixed weight or intercept must be between 0 and 1",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The fixed parameters with their values, e.g. "Weight=0,Intercept=0.01"
+impl Display for Fixed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = ["Alpha", "Beta", "Weight", "Intercept", "SelCoef"];
+        let fixed: Vec<String> = names
+            .iter()
+            .zip(self.to_vec())
+            .filter_map(|(name, value)| value.map(|v| format!("{name}={v}")))
+            .collect();
+        write!(f, "{}", fixed.join(","))
+    }
 }
+
 #[derive(Clone, Debug)]
 pub struct Model {
     pub alpha: f64,
     pub beta: f64,
     pub weight: f64,
     pub intercept: f64,
+    /// Only estimated by the selection models
+    pub sel_coef: Option<f64>,
+    /// Set by the estimation if a parameter ended up on the boundary of its valid range, see [`Problem::is_on_boundary`]
+    pub on_boundary: bool,
+}
+
+/// The space in which the optimizer searches for the parameters of a model
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Parameterization {
+    /// The parameters themselves, without any constraints
+    #[default]
+    Natural,
+    /// Log-odds for the rates, the weight and the selection coefficient and log for the intercept.
+    ///
+    /// Every point the optimizer visits maps back to biologically valid parameters.
+    Transformed,
+    /// Like [`Parameterization::Transformed`], but with the log10-odds for the rates.
+    ///
+    /// For rates as small as the epimutation rates, that is their log10, the scale the starting points are drawn on.
+    Log10,
+}
+
+/// The epimutation models that can be fitted to a pedigree, named after their counterparts in the R package.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ModelKind {
+    /// ABneutral: Epimutations are selectively neutral
+    Neutral,
+    /// ABselectMM: Selection against the MM state
+    SelectMM,
+    /// ABselectUU: Selection against the UU state
+    SelectUU,
+    /// ABsoma: Epimutations accumulate in somatic lineages (e.g. branches of a tree), times can be in any unit
+    Soma,
+}
+
+impl ModelKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Neutral => "ABneutral",
+            Self::SelectMM => "ABselectMM",
+            Self::SelectUU => "ABselectUU",
+            Self::Soma => "ABsoma",
+        }
+    }
+
+    pub fn has_selection(&self) -> bool {
+        matches!(self, Self::SelectMM | Self::SelectUU)
+    }
+
+    /// Number of parameters estimated by the optimizer
+    pub fn n_params(&self) -> usize {
+        if self.has_selection() {
+            5
+        } else {
+            4
+        }
+    }
+}
+
+impl Display for ModelKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// How the lines of the pedigree are propagated from one generation to the next.
+///
+/// Changes the generation matrix and the state vector at G0. The somatic model has no generations and ignores it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum MatingSystem {
+    /// Self-fertilisation from a single founder
+    #[default]
+    Selfing,
+    /// Crossing to a reference line in every generation, starting from a founder that was crossed to the reference as well
+    Outcrossing,
+    /// Self-fertilisation, but starting from a founder that is a cross between the line and the reference
+    HeterozygousFounder,
+}
+
+impl Display for MatingSystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Selfing => "Selfing",
+            Self::Outcrossing => "Outcrossing",
+            Self::HeterozygousFounder => "HeterozygousFounder",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// How the bootstrap generates new data sets to refit the model to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum BootstrapMode {
+    /// Resample the residuals with replacement and add them to the predicted divergence
+    #[default]
+    Residual,
+    /// Simulate the states of the sites down the genealogy of the samples under the fitted model
+    Parametric,
+    /// Resample whole samples, with all their pairs, as pairs sharing a sample are not independent
+    Samples,
+    /// Resample whole lineages, the samples descending from the same offspring of a founder
+    Lineages,
+}
+
+impl Display for BootstrapMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Residual => "Residual",
+            Self::Parametric => "Parametric",
+            Self::Samples => "Samples",
+            Self::Lineages => "Lineages",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// What the optimizer minimizes to fit a model to the pedigree
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Estimator {
+    /// Least squares of the divergences, as in the R package
+    #[default]
+    LeastSquares,
+    /// Least squares, with every pair weighted by its number of compared sites relative to the average.
+    /// Pairs compared over a handful of sites, e.g. in small windows, no longer count as much as those compared over thousands
+    WeightedLeastSquares,
+    /// Maximum likelihood, with the discordant alleles of every pair binomially distributed over its compared sites.
+    /// Pairs with many compared sites weigh more
+    Binomial,
+    /// Maximum likelihood, with a beta-binomial that allows the divergence to vary more between pairs than the binomial does.
+    /// The overdispersion is estimated along with the model
+    BetaBinomial,
+}
+
+/// How the divergence that miscalled sites add to every pair is dealt with, see [`crate::misclassification`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ErrorModel {
+    /// Take the called status as the truth, the intercept absorbs the miscalls
+    #[default]
+    None,
+    /// Subtract the expected divergence from miscalled sites from the observed divergence before fitting
+    Subtract,
+    /// Add the expected divergence from miscalled sites to the divergence the model predicts for every pair
+    Explicit,
+}
+
+impl Display for ErrorModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::None => "None",
+            Self::Subtract => "Subtract",
+            Self::Explicit => "Explicit",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl Estimator {
+    /// Whether the estimator needs the number of compared sites of every pair
+    pub fn needs_sites(&self) -> bool {
+        !matches!(self, Self::LeastSquares)
+    }
+
+    /// Whether the estimator maximizes a likelihood instead of minimizing squared errors
+    pub fn is_likelihood(&self) -> bool {
+        matches!(self, Self::Binomial | Self::BetaBinomial)
+    }
+
+    /// Parameters the estimator estimates on top of those of the model: The overdispersion of the beta-binomial
+    pub fn n_params(&self) -> usize {
+        usize::from(*self == Self::BetaBinomial)
+    }
+}
+
+impl Display for Estimator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::LeastSquares => "LeastSquares",
+            Self::WeightedLeastSquares => "WeightedLeastSquares",
+            Self::Binomial => "Binomial",
+            Self::BetaBinomial => "BetaBinomial",
+        };
+        write!(f, "{name}")
+    }
 }
 
 pub type PredictedDivergence = Vec<f64>;
@@ -58,7 +338,17 @@ impl Display for Model {
             f,
             "Model:\n\tAlpha: {}\n\tBeta: {}\n\tWeight: {}\n\tIntercept: {}",
             self.alpha, self.beta, self.weight, self.intercept
-        )
+        )?;
+        if let Some(sel_coef) = self.sel_coef {
+            write!(f, "\n\tSelection coefficient: {sel_coef}")?;
+        }
+        if self.on_boundary {
+            write!(
+                f,
+                "\n\tWarning: The fit ended on the boundary of the parameter space"
+            )?;
+        }
+        Ok(())
     }
 }
 
@@ -70,19 +360,38 @@ impl Default for Model {
             beta: 0.0001180614,
             weight: 0.03693534,
             intercept: 0.003023981,
+            sel_coef: None,
+            on_boundary: false,
         }
     }
 }
 
+/// Random number generator for one iteration of a multistart or bootstrap loop.
+///
+/// It only depends on the seed, the stream (e.g. the estimation of a model or its bootstrap) and the index of the iteration,
+/// so the results are the same no matter how the iterations are distributed over the threads.
+pub fn iteration_rng(seed: u64, stream: u64, iteration: usize) -> StdRng {
+    // SplitMix64 finalizer, so that neighbouring iterations get unrelated seeds
+    let mix = |mut z: u64| {
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    };
+    let golden = 0x9e3779b97f4a7c15_u64;
+    let z = mix(seed.wrapping_add(golden.wrapping_mul(stream + 1)));
+    StdRng::seed_from_u64(mix(
+        z.wrapping_add(golden.wrapping_mul(iteration as u64 + 1))
+    ))
+}
+
 impl Model {
-    pub fn new(max_divergence: f64) -> Self {
+    pub fn new(max_divergence: f64, rng: &mut impl Rng) -> Self {
         let mut max = max_divergence;
         if max_divergence <= 0.0 {
             println!("Sample has a maximum divergence of zero! Check your data");
             max = 0.1;
         }
 
-        let mut rng = thread_rng();
         let alpha = 10.0_f64.powf(rng.sample(Uniform::new(-9.0, -2.0)));
         let beta = 10.0_f64.powf(rng.sample(Uniform::new(-9.0, -2.0)));
         let weight = rng.sample(Uniform::new(0.0, 0.1));
@@ -92,13 +401,24 @@ impl Model {
             beta,
             weight,
             intercept,
+            sel_coef: None,
+            on_boundary: false,
+        }
+    }
+
+    /// Draw random starting values for the given kind of model, including a selection coefficient for the selection models.
+    pub fn new_for(kind: ModelKind, max_divergence: f64, rng: &mut impl Rng) -> Self {
+        let mut model = Model::new(max_divergence, rng);
+        if kind.has_selection() {
+            model.sel_coef = Some(rng.sample(Uniform::new(0.0, 0.5)));
         }
+        model
     }
+
     /// Returns a new model with parameters that are randomly varied by up to 5% of their original value.
     ///
     /// I made sure to check that only positive, non-zero floats can be passed, but this is a nicer way to handle errors as the panic is not well-readable
-    pub fn vary(&self) -> Self {
-        let mut rng = thread_rng();
+    pub fn vary(&self, rng: &mut impl Rng) -> Self {
         const VARIANCE: f64 = 0.1; // 10% variance, somewhat arbitrarily chosen, but even 0.5 does not have a huge effect on the results.
 
         fn var(n: f64) -> Uniform<f64> {
@@ -127,8 +447,11 @@ impl Model {
         )
     }
 
+    /// Alpha, Beta, Weight, Intercept and, if present, the selection coefficient
     pub fn to_vec(&self) -> Vec<f64> {
-        vec![self.alpha, self.beta, self.weight, self.intercept]
+        let mut v = vec![self.alpha, self.beta, self.weight, self.intercept];
+        v.extend(self.sel_coef);
+        v
     }
     pub fn from_vec(v: &[f64]) -> Self {
         Model {
@@ -136,9 +459,23 @@ impl Model {
             beta: v[1],
             weight: v[2],
             intercept: v[3],
+            sel_coef: v.get(4).copied(),
+            on_boundary: false,
         }
     }
 
+    /// Whether any parameter is (numerically) on the boundary of its valid range: rates, weight and selection coefficient in \[0, 1\], intercept >= 0.
+    ///
+    /// Such a fit usually means the model does not describe the data well or the optimizer got stuck.
+    pub fn is_on_boundary(&self) -> bool {
+        const EPSILON: f64 = 1e-10;
+        [self.alpha, self.beta, self.weight]
+            .into_iter()
+            .chain(self.sel_coef)
+            .any(|p| !(EPSILON..=1.0 - EPSILON).contains(&p))
+            || self.intercept < EPSILON
+    }
+
     pub fn to_array(&self) -> Array1<f64> {
         Array1::from(self.to_vec())
     }
@@ -160,10 +497,14 @@ impl Model {
     pub fn to_file(&self, path: &Path) -> std::io::Result<()> {
         println!("Writing model to file: {}", path.display());
         let mut file = File::create(path).unwrap();
-        let content = format!(
+        let mut content = format!(
             "Alpha {}\nBeta {}\nWeight {}\n Intercept {}\n",
             self.alpha, self.beta, self.weight, self.intercept
         );
+        if let Some(sel_coef) = self.sel_coef {
+            content += &format!("SelCoef {sel_coef}\n");
+        }
+        content += &format!("OnBoundary {}\n", self.on_boundary);
 
         file.write_all(content.as_bytes())
     }
@@ -184,53 +525,474 @@ impl Default for Problem {
             p_uu,
             eqp_weight,
             eqp,
+            kind: ModelKind::Neutral,
+            mating: MatingSystem::Selfing,
+            parameterization: Parameterization::Natural,
+            fixed: Fixed::default(),
+            estimator: Estimator::LeastSquares,
         }
     }
 }
 
+impl Problem {
+    /// Set up the estimation of a model from a pedigree with `p0uu` as the proportion of unmethylated sites at G0.
+    ///
+    /// The optimizer works in the transformed parameter space with the rates on the log10 scale, so all estimates are valid.
+    pub fn new(
+        pedigree: Pedigree,
+        p0uu: f64,
+        eqp: f64,
+        eqp_weight: f64,
+        kind: ModelKind,
+        mating: MatingSystem,
+        estimator: Estimator,
+    ) -> Self {
+        let p0mm = 1.0 - p0uu;
+        let p0um = 0.0;
+
+        assert_eq!(p0mm + p0uu + p0um, 1.0);
+
+        Problem {
+            pedigree,
+            eqp_weight,
+            eqp,
+            p_mm: p0mm,
+            p_um: p0um,
+            p_uu: p0uu,
+            kind,
+            mating,
+            parameterization: Parameterization::Log10,
+            fixed: Fixed::default(),
+            estimator,
+        }
+    }
+
+    /// Number of parameters the optimizer estimates, i.e. those of the model that are not fixed
+    pub fn n_params(&self) -> usize {
+        self.kind.n_params() - self.fixed.count(self.kind.n_params())
+    }
+
+    /// Whether one of the estimated parameters of a model lies on the boundary of its valid range, see [`Model::is_on_boundary`].
+    ///
+    /// Fixed parameters are wherever they were put, e.g. a weight of zero.
+    pub fn is_on_boundary(&self, m: &Model) -> bool {
+        self.free(m).is_on_boundary()
+    }
+
+    /// Random model close to `m` for the starting points of a refit, see [`Model::vary`]. The fixed parameters stay as they are
+    pub fn vary(&self, m: &Model, rng: &mut impl Rng) -> Model {
+        let mut varied = self.free(m).vary(rng);
+        self.fixed.apply(&mut varied);
+        varied
+    }
+
+    /// The model with its fixed parameters replaced by a value well inside their valid range
+    fn free(&self, m: &Model) -> Model {
+        let interior = |p: Option<f64>| p.map(|_| 0.5);
+        let mut free = m.clone();
+        Fixed {
+            alpha: interior(self.fixed.alpha),
+            beta: interior(self.fixed.beta),
+            weight: interior(self.fixed.weight),
+            intercept: interior(self.fixed.intercept),
+            sel_coef: interior(self.fixed.sel_coef),
+        }
+        .apply(&mut free);
+        free
+    }
+
+    /// Point in the optimizer's parameter space for a model, without the fixed parameters
+    pub fn to_params(&self, m: &Model) -> Vec<f64> {
+        let fixed = self.fixed.to_vec();
+        self.to_all_params(m)
+            .into_iter()
+            .zip(fixed)
+            .filter(|(_, f)| f.is_none())
+            .map(|(p, _)| p)
+            .collect()
+    }
+
+    fn to_all_params(&self, m: &Model) -> Vec<f64> {
+        match self.parameterization {
+            Parameterization::Natural => m.to_vec(),
+            Parameterization::Transformed | Parameterization::Log10 => {
+                // Keep the starting points of the optimizer finite
+                let logit = |p: f64| {
+                    let p = p.clamp(f64::MIN_POSITIVE, 1.0 - f64::EPSILON);
+                    (p / (1.0 - p)).ln()
+                };
+                let rate = |p: f64| match self.parameterization {
+                    Parameterization::Log10 => logit(p) / LN_10,
+                    _ => logit(p),
+                };
+                let mut v = vec![
+                    rate(m.alpha),
+                    rate(m.beta),
+                    logit(m.weight),
+                    m.intercept.max(f64::MIN_POSITIVE).ln(),
+                ];
+                v.extend(m.sel_coef.map(logit));
+                v
+            }
+        }
+    }
+
+    /// Model for a point in the optimizer's parameter space, with the fixed parameters filled in
+    pub fn to_model(&self, p: &[f64]) -> Model {
+        let mut free = p.iter();
+        let all: Vec<f64> = self.fixed.to_vec()[..self.kind.n_params()]
+            .iter()
+            .map(|f| match f {
+                Some(_) => 0.0,
+                None => *free.next().expect("Too few parameters for the model"),
+            })
+            .collect();
+        let mut m = self.to_all_model(&all);
+        self.fixed.apply(&mut m);
+        m
+    }
+
+    fn to_all_model(&self, p: &[f64]) -> Model {
+        match self.parameterization {
+            Parameterization::Natural => Model::from_vec(p),
+            Parameterization::Transformed | Parameterization::Log10 => {
+                let logistic = |x: f64| 1.0 / (1.0 + (-x).exp());
+                let rate = |x: f64| match self.parameterization {
+                    Parameterization::Log10 => logistic(x * LN_10),
+                    _ => logistic(x),
+                };
+                Model {
+                    alpha: rate(p[0]),
+                    beta: rate(p[1]),
+                    weight: logistic(p[2]),
+                    intercept: p[3].exp(),
+                    sel_coef: p.get(4).copied().map(logistic),
+                    on_boundary: false,
+                }
+            }
+        }
+    }
+
+    /// Transition matrix of one generation under this problem's model
+    pub fn genmatrix(&self, m: &Model) -> Array2<f64> {
+        let sel_coef = m.sel_coef.unwrap_or(0.0);
+        let neutral = match self.mating {
+            MatingSystem::Selfing | MatingSystem::HeterozygousFounder => genmatrix(m.alpha, m.beta),
+            MatingSystem::Outcrossing => self.genmatrix_outcross(m),
+        };
+        match self.kind {
+            ModelKind::Neutral => neutral,
+            ModelKind::SelectMM => select(neutral, [1.0, 1.0, 1.0 - sel_coef]),
+            ModelKind::SelectUU => select(neutral, [1.0 - sel_coef, 1.0, 1.0]),
+            ModelKind::Soma => genmatrix_soma(m.alpha, m.beta),
+        }
+    }
+
+    /// Transition matrix over `t` generations (or units of time for the somatic model)
+    pub fn transition(&self, m: &Model, t: f64) -> Result<Array2<f64>, Error> {
+        match self.kind {
+            ModelKind::Soma => soma_matrix_power(m.alpha, m.beta, t),
+            _ => matrix_power(&self.genmatrix(m), t),
+        }
+    }
+
+    fn genmatrix_outcross(&self, m: &Model) -> Array2<f64> {
+        genmatrix_outcross(m.alpha, m.beta, self.p_mm, self.p_um, self.p_uu)
+    }
+
+    /// State probabilities (UU, UM, MM) at G0 under this problem's mating system
+    pub fn sv_gzero(&self, m: &Model) -> Array1<f64> {
+        let founder = founder_state(self.p_mm, self.p_um, self.p_uu, m.weight);
+        match self.mating {
+            MatingSystem::Selfing => founder,
+            // The founder itself is the offspring of a cross to the reference
+            MatingSystem::Outcrossing | MatingSystem::HeterozygousFounder => {
+                founder.dot(&self.genmatrix_outcross(m))
+            }
+        }
+    }
+
+    /// Theoretical divergence of every pair in the pedigree under this problem's model.
+    ///
+    /// If the pedigree holds the divergence expected from miscalled sites, it is part of the prediction.
+    pub fn divergence(&self, m: &Model) -> Result<Divergence, Error> {
+        let mut divergence = match (self.kind, self.mating) {
+            (ModelKind::Neutral, MatingSystem::Selfing) => divergence(
+                &self.pedigree,
+                self.p_mm,
+                self.p_um,
+                self.p_uu,
+                m.alpha,
+                m.beta,
+                m.weight,
+            ),
+            (ModelKind::Soma, _) => divergence_soma(
+                &self.pedigree,
+                self.p_mm,
+                self.p_um,
+                self.p_uu,
+                m.alpha,
+                m.beta,
+                m.weight,
+            ),
+            _ => divergence_with_genmatrix(&self.pedigree, &self.sv_gzero(m), &self.genmatrix(m)),
+        }?;
+        if let Some(misclassification) = self.pedigree.misclassification() {
+            for (d, e) in divergence.dt1t2.iter_mut().zip(misclassification) {
+                *d += e;
+            }
+        }
+        Ok(divergence)
+    }
+
+    /// Equilibrium state probabilities (Pr(MM), Pr(UM), Pr(UU)) of a model
+    pub fn equilibrium(&self, m: &Model) -> (f64, f64, f64) {
+        match (self.kind, self.mating) {
+            (ModelKind::Neutral, MatingSystem::Selfing) => (m.est_mm(), m.est_um(), m.est_uu()),
+            _ => {
+                let [uu, um, mm] = steady_state_distribution(&self.genmatrix(m));
+                (mm, um, uu)
+            }
+        }
+    }
+
+    /// Methylation level at the equilibrium of a model, Pr(MM) + Pr(UM) / 2
+    pub fn steady_state(&self, m: &Model) -> f64 {
+        let (mm, um, _) = self.equilibrium(m);
+        mm + 0.5 * um
+    }
+
+    /// How badly a model with the `predicted` divergence (without intercept) fits the pedigree, without the equilibrium penalty:
+    /// The (weighted) least squares or the negative log-likelihood, depending on the estimator.
+    pub fn lack_of_fit(&self, m: &Model, predicted: &[f64]) -> Result<f64, Error> {
+        if !self.estimator.needs_sites() {
+            return Ok(predicted
+                .iter()
+                .zip(self.pedigree.column(3))
+                .map(|(div, ped)| (ped - m.intercept - div).powi(2))
+                .sum());
+        }
+
+        let pairs = self.pairs(m, predicted)?;
+        Ok(match self.estimator {
+            Estimator::WeightedLeastSquares => {
+                // Relative to the average, so that the cost stays on the scale of the unweighted one
+                let mean_sites = pairs.iter().map(|(_, n, _)| n).sum::<f64>() / pairs.len() as f64;
+                pairs
+                    .iter()
+                    .map(|(d, n, p)| n / mean_sites * (d - p).powi(2))
+                    .sum()
+            }
+            Estimator::BetaBinomial => {
+                let rho = likelihood::overdispersion(&pairs);
+                pairs
+                    .iter()
+                    .map(|(d, n, p)| likelihood::beta_binomial(*d, *n, *p, rho))
+                    .sum()
+            }
+            _ => pairs
+                .iter()
+                .map(|(d, n, p)| likelihood::binomial(*d, *n, *p))
+                .sum(),
+        })
+    }
+
+    /// How badly a model fits the pedigree, with the divergence it predicts, see [`Problem::lack_of_fit`].
+    ///
+    /// Unlike the cost, it leaves out the equilibrium penalty, so that it is a pure likelihood for [`Problem::log_likelihood`].
+    pub fn lack_of_fit_of(&self, m: &Model) -> Result<f64, Error> {
+        self.lack_of_fit(m, &self.divergence(m)?.dt1t2)
+    }
+
+    /// Observed divergence, compared sites and predicted divergence (with intercept) of every pair, for the estimators that need the sites
+    fn pairs(&self, m: &Model, predicted: &[f64]) -> Result<Vec<(f64, f64, f64)>, Error> {
+        let Some(sites) = self.pedigree.sites() else {
+            return Err(Error::Simple(
+                "The estimator needs the number of compared sites of every pair, which only pedigrees built from methylomes have",
+            ));
+        };
+        Ok(self
+            .pedigree
+            .column(3)
+            .iter()
+            .zip(sites)
+            .zip(predicted)
+            .map(|((ped, n), div)| (*ped, *n, m.intercept + div))
+            .collect())
+    }
+
+    /// Equilibrium penalty of a model: How far the predicted proportion of unmethylated sites is from `eqp`, weighted by `eqp_weight`.
+    ///
+    /// Together with the [`Problem::lack_of_fit`], it makes up the cost the optimizer minimizes.
+    pub fn penalty(&self, m: &Model) -> Result<f64, Error> {
+        Ok(self.penalty_of(&self.divergence(m)?))
+    }
+
+    fn penalty_of(&self, divergence: &Divergence) -> f64 {
+        // Added for every pair, like the squared errors
+        let n = self.pedigree.nrows() as f64;
+        n * self.eqp_weight * n * (divergence.p_uu - self.eqp).powi(2)
+    }
+
+    /// Log-likelihood of a fit with the given cost, up to a constant.
+    ///
+    /// The likelihood estimators minimize the negative log-likelihood. For (weighted) least squares,
+    /// the errors are normal with the variance integrated out under its Jeffreys prior, which leaves -n/2 * ln(cost).
+    pub fn log_likelihood(&self, cost: f64) -> f64 {
+        match self.estimator {
+            Estimator::LeastSquares | Estimator::WeightedLeastSquares => {
+                -(self.pedigree.nrows() as f64) / 2.0 * cost.ln()
+            }
+            Estimator::Binomial | Estimator::BetaBinomial => -cost,
+        }
+    }
+
+    /// Overdispersion of the beta-binomial estimator for a model, `None` for the other estimators
+    pub fn overdispersion(&self, m: &Model) -> Result<Option<f64>, Error> {
+        if self.estimator != Estimator::BetaBinomial {
+            return Ok(None);
+        }
+        let pairs = self.pairs(m, &self.divergence(m)?.dt1t2)?;
+        Ok(Some(likelihood::overdispersion(&pairs)))
+    }
+
+    /// Estimates of a model in the columns of a bootstrap: Alpha, Beta, Weight, Intercept, Pr(MM), Pr(UM), Pr(UU) (, SelCoef)
+    pub fn estimates(&self, m: &Model) -> Vec<f64> {
+        let (pr_mm, pr_um, pr_uu) = self.equilibrium(m);
+        let mut r = vec![m.alpha, m.beta, m.weight, m.intercept, pr_mm, pr_um, pr_uu];
+        r.extend(m.sel_coef);
+        r
+    }
+}
+
 impl CostFunction for Problem {
     type Output = f64;
     type Param = Vec<f64>;
     fn cost(&self, p: &Self::Param) -> Result<Self::Output, argmin::core::Error> {
-        let p = Model::from_vec(p);
-        let divergence = divergence(
-            &self.pedigree,
-            self.p_mm,
-            self.p_um,
-            self.p_uu,
-            p.alpha,
-            p.beta,
-            p.weight,
-        );
-
-        let mut square_sum = 0.0;
-
-        for (div, ped) in divergence.dt1t2.iter().zip(self.pedigree.column(3)) {
-            square_sum += (ped - p.intercept - div).powi(2)
-                + self.eqp_weight
-                    * self.pedigree.nrows() as f64
-                    * (divergence.p_uu - self.eqp).powi(2);
+        let p = self.to_model(p);
+        let divergence = match self.divergence(&p) {
+            // Parameters for which fractional generations are undefined are simply a bad fit
+            Err(Error::FractionalPower(_)) => return Ok(f64::INFINITY),
+            divergence => divergence?,
+        };
+
+        let cost = self.lack_of_fit(&p, &divergence.dt1t2)? + self.penalty_of(&divergence);
+
+        // Degenerate parameters, e.g. rates of one, are just as bad a fit, and the optimizers can't order NaN
+        if cost.is_nan() {
+            return Ok(f64::INFINITY);
         }
 
-        Ok(square_sum)
+        Ok(cost)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::fmt::Debug;
-
     use super::*;
 
-    fn cost_function_tester<C: CostFunction>(c: C)
+    fn cost_function_tester<C: CostFunction<Output = f64>>(c: C)
     where
         <C as argmin::core::CostFunction>::Param: From<Vec<f64>>,
-        <C as argmin::core::CostFunction>::Output: std::cmp::PartialEq<f64> + Debug,
     {
         let param = Model::default().to_vec().into();
         let result = C::cost(&c, &param);
         let result = result.unwrap();
-        assert_eq!(result, 0.0006700888539608879);
+        // The 3x3 products don't go through BLAS, so the last bits depend on the summation order
+        assert!(
+            (result - 0.0006700888539608879).abs() < 1e-15,
+            "cost was {result}"
+        );
+    }
+
+    #[test]
+    fn transformed_parameters_are_valid() {
+        let problem = Problem {
+            parameterization: Parameterization::Transformed,
+            ..Problem::default()
+        };
+        let model = Model {
+            sel_coef: Some(0.2),
+            ..Model::default()
+        };
+        let round_trip = problem.to_model(&problem.to_params(&model));
+        for (a, b) in round_trip.to_vec().iter().zip(model.to_vec()) {
+            assert_close!(a, b);
+        }
+
+        // Wherever the optimizer goes, the parameters stay valid
+        let model = problem.to_model(&[-50.0, 3.0, 40.0, -2.0, 0.0]);
+        assert!(model.alpha > 0.0 && model.beta < 1.0 && model.weight <= 1.0);
+        assert!(model.intercept > 0.0);
+        assert!(model.is_on_boundary());
+        assert!(!Model::default().is_on_boundary());
+    }
+
+    #[test]
+    fn log10_parameters_are_the_log10_of_small_rates() {
+        let problem = Problem {
+            parameterization: Parameterization::Log10,
+            ..Problem::default()
+        };
+        let model = Model::default();
+        let params = problem.to_params(&model);
+        assert_close!(params[0], model.alpha.log10());
+        assert_close!(params[1], model.beta.log10());
+
+        let round_trip = problem.to_model(&params);
+        for (a, b) in round_trip.to_vec().iter().zip(model.to_vec()) {
+            assert_close!(a, b);
+        }
+    }
+
+    #[test]
+    fn equal_sites_weigh_equally() {
+        let unweighted = Problem::default();
+        let with_sites = |sites: Vec<f64>| {
+            let mut pedigree = unweighted.pedigree.clone();
+            pedigree.push_column(Array1::from(sites).view()).unwrap();
+            Problem {
+                pedigree,
+                estimator: Estimator::WeightedLeastSquares,
+                ..Problem::default()
+            }
+        };
+        let n = unweighted.pedigree.nrows();
+        let model = Model::default();
+        let predicted = unweighted.divergence(&model).unwrap().dt1t2;
+        let lack_of_fit = |problem: &Problem| problem.lack_of_fit(&model, &predicted).unwrap();
+
+        let equal = lack_of_fit(&with_sites(vec![5000.0; n]));
+        assert_close!(equal, lack_of_fit(&unweighted));
+
+        // A pair without sites doesn't count at all
+        let mut sites = vec![5000.0; n];
+        sites[0] = 0.0;
+        let residual = unweighted.pedigree[[0, 3]] - model.intercept - predicted[0];
+        let rest = lack_of_fit(&unweighted) - residual.powi(2);
+        let mean = 5000.0 * (n - 1) as f64 / n as f64;
+        let weighted = lack_of_fit(&with_sites(sites));
+        assert_close!(weighted, 5000.0 / mean * rest);
+    }
+
+    #[test]
+    fn steady_state_of_the_fitted_model() {
+        let model = Model {
+            sel_coef: Some(0.5),
+            ..Model::default()
+        };
+        let neutral = Problem::default();
+        assert_close!(
+            neutral.steady_state(&model),
+            crate::alphabeta::steady_state(model.alpha, model.beta)
+        );
+
+        // Selection against methylated alleles lowers the methylation level at equilibrium
+        let select_mm = Problem {
+            kind: ModelKind::SelectMM,
+            ..Problem::default()
+        };
+        assert!(select_mm.steady_state(&model) < neutral.steady_state(&model));
     }
 
     #[test]